@@ -0,0 +1,37 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/* `GIT_HASH` for `/version`, `unknown` when built outside of a git checkout */
+fn main() {
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| "unknown".into());
+
+    println!("cargo:rustc-env=GIT_HASH={}", hash);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    // commits move the branch HEAD points to, a loose ref or one of the packed ones
+    let head_ref = fs::read_to_string(".git/HEAD")
+        .ok()
+        .and_then(|head| head.trim().strip_prefix("ref: ").map(String::from));
+    if let Some(head_ref) = head_ref {
+        let path = Path::new(".git").join(head_ref);
+        // a packed ref gets a loose file in its directory on the next commit
+        watch(&path).or_else(|| path.parent().and_then(watch));
+    }
+    watch(Path::new(".git/packed-refs"));
+}
+
+/* a missing path would rerun this on every build */
+fn watch(path: &Path) -> Option<()> {
+    if !path.exists() {
+        return None;
+    }
+    println!("cargo:rerun-if-changed={}", path.display());
+    Some(())
+}
@@ -0,0 +1,205 @@
+use actix::prelude::*;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::{self, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// One accepted request, as received. Archived one per line.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchiveRecord {
+    /// millis since epoch
+    pub received: u64,
+    pub ip: Option<IpAddr>,
+    pub envelope: Value,
+}
+
+impl Message for ArchiveRecord {
+    type Result = ();
+}
+
+struct ArchiveFile {
+    path: PathBuf,
+    writer: GzEncoder<File>,
+    opened: Instant,
+    written: u64,
+}
+
+/// Appends records to gzip-compressed NDJSON files in `dir`, starting a new file once
+/// the current one holds `rotate_bytes` of (uncompressed) data or is `rotate_after` old.
+pub struct Archiver {
+    dir: PathBuf,
+    rotate_bytes: u64,
+    rotate_after: Duration,
+    current: Option<ArchiveFile>,
+}
+
+impl Archiver {
+    pub fn new(dir: PathBuf, rotate_bytes: u64, rotate_after: Duration) -> Self {
+        Archiver {
+            dir,
+            rotate_bytes,
+            rotate_after,
+            current: None,
+        }
+    }
+
+    /* names sort in the order files were opened, the sequence number tells apart files
+     * opened within the same millisecond; an existing file is never overwritten */
+    fn open(&self) -> io::Result<ArchiveFile> {
+        fs::create_dir_all(&self.dir)?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        for seq in 0.. {
+            let path = self.dir.join(format!(
+                "golem-monitor-{}-{:03}-{:03}.ndjson.gz",
+                now.as_secs(),
+                now.subsec_millis(),
+                seq
+            ));
+            let file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            };
+            info!("archiving requests to {}", path.display());
+
+            return Ok(ArchiveFile {
+                writer: GzEncoder::new(file, Compression::default()),
+                path,
+                opened: Instant::now(),
+                written: 0,
+            });
+        }
+        unreachable!()
+    }
+
+    fn close(&mut self) {
+        if let Some(file) = self.current.take() {
+            if let Err(e) = file.writer.finish() {
+                error!("closing archive {}: {}", file.path.display(), e);
+            }
+        }
+    }
+
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        let rotate = match self.current {
+            Some(ref file) => {
+                file.written >= self.rotate_bytes || file.opened.elapsed() >= self.rotate_after
+            }
+            None => false,
+        };
+        if rotate {
+            self.close();
+        }
+        if self.current.is_none() {
+            self.current = Some(self.open()?);
+        }
+
+        let file = self.current.as_mut().unwrap();
+        file.writer.write_all(line)?;
+        file.written += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl Actor for Archiver {
+    type Context = SyncContext<Self>;
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        self.close();
+    }
+}
+
+impl Handler<ArchiveRecord> for Archiver {
+    type Result = ();
+
+    fn handle(&mut self, msg: ArchiveRecord, _: &mut Self::Context) {
+        let mut line = match serde_json::to_vec(&msg) {
+            Ok(line) => line,
+            Err(e) => return warn!("unable to archive request: {}", e),
+        };
+        line.push(b'\n');
+
+        if let Err(e) = self.append(&line) {
+            error!("archive write error: {}", e);
+            // drop the broken file, next record will open a fresh one
+            self.current = None;
+        }
+    }
+}
+
+/* reads records of a single archive file, stops at the first unreadable line */
+fn read_archive(path: String) -> impl Iterator<Item = ArchiveRecord> {
+    let lines = match File::open(&path) {
+        Ok(file) => Some(BufReader::new(GzDecoder::new(file)).lines()),
+        Err(e) => {
+            error!("unable to open {}: {}", path, e);
+            None
+        }
+    };
+    let path_parse = path.clone();
+
+    lines
+        .into_iter()
+        .flatten()
+        .scan((), move |_, line| match line {
+            Ok(line) => Some(line),
+            Err(e) => {
+                warn!("{}: archive truncated ({})", path, e);
+                None
+            }
+        })
+        .filter_map(move |line| match serde_json::from_str(&line) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                warn!("{}: skipping invalid record ({})", path_parse, e);
+                None
+            }
+        })
+}
+
+pub fn read_archives(paths: Vec<String>) -> impl Iterator<Item = ArchiveRecord> {
+    paths.into_iter().flat_map(read_archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn write_and_read_back() {
+        let dir = env::temp_dir().join(format!("golem-monitor-archive-{}", ::std::process::id()));
+        let mut archiver = Archiver::new(dir.clone(), 1, Duration::from_secs(3600));
+
+        for i in 0..3 {
+            let record = ArchiveRecord {
+                received: i,
+                ip: Some("10.0.0.1".parse().unwrap()),
+                envelope: json!({"proto_ver": 1, "data": {"cliid": "n1"}}),
+            };
+            let mut line = serde_json::to_vec(&record).unwrap();
+            line.push(b'\n');
+            archiver.append(&line).unwrap();
+        }
+        archiver.close();
+
+        let mut paths: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path().to_string_lossy().into_owned())
+            .collect();
+        paths.sort();
+        // rotated after every record
+        assert_eq!(paths.len(), 3);
+
+        let received: Vec<u64> = read_archives(paths).map(|r| r.received).collect();
+        assert_eq!(received, vec![0, 1, 2]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::http::header::{self, HttpDate, LastModified};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use redis_tools::ChangeMarker;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/* rendered bodies are rebuilt at least this often, so inactive nodes still expire */
+const CACHE_TTL: Duration = Duration::from_secs(30);
+const CACHE_MAX_ENTRIES: usize = 16;
+
+fn etag(marker: &ChangeMarker) -> String {
+    match marker.window {
+        0 => format!("\"{}\"", marker.generation),
+        window => format!("\"{}-{}\"", marker.generation, window),
+    }
+}
+
+fn last_modified(marker: &ChangeMarker) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(marker.modified)
+}
+
+/// Whether the client already holds the representation of `marker`,
+/// as told by `If-None-Match` or, in its absence, `If-Modified-Since`.
+pub fn is_not_modified<S>(req: &HttpRequest<S>, marker: &ChangeMarker) -> bool {
+    if let Some(if_none_match) = req.headers().get(header::IF_NONE_MATCH) {
+        let etag = etag(marker);
+        return if_none_match
+            .to_str()
+            .map(|tags| {
+                tags.split(',')
+                    .map(str::trim)
+                    .any(|tag| tag == "*" || tag == etag || tag.trim_start_matches("W/") == etag)
+            })
+            .unwrap_or(false);
+    }
+
+    let since = req
+        .headers()
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<HttpDate>().ok());
+    match since {
+        // http dates have a second resolution
+        Some(since) => {
+            marker.modified / 1000
+                <= SystemTime::from(since)
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0)
+        }
+        None => false,
+    }
+}
+
+pub fn with_validators<'a>(
+    builder: &'a mut HttpResponseBuilder,
+    marker: &ChangeMarker,
+) -> &'a mut HttpResponseBuilder {
+    builder.header(header::ETAG, etag(marker)).header(
+        header::LAST_MODIFIED,
+        LastModified(last_modified(marker).into()),
+    )
+}
+
+pub fn not_modified(marker: &ChangeMarker) -> HttpResponse {
+    with_validators(&mut HttpResponse::NotModified(), marker).finish()
+}
+
+struct Rendered {
+    marker: ChangeMarker,
+    rendered_at: Instant,
+    body: Bytes,
+}
+
+/// Rendered response bodies keyed by query string, valid until nodes change.
+#[derive(Default)]
+pub struct RenderCache {
+    entries: HashMap<String, Rendered>,
+}
+
+impl RenderCache {
+    pub fn get(&mut self, key: &str, marker: &ChangeMarker) -> Option<Bytes> {
+        let fresh = match self.entries.get(key) {
+            Some(rendered) => {
+                rendered.marker == *marker && rendered.rendered_at.elapsed() < CACHE_TTL
+            }
+            None => return None,
+        };
+        if fresh {
+            self.entries.get(key).map(|rendered| rendered.body.clone())
+        } else {
+            self.entries.remove(key);
+            None
+        }
+    }
+
+    pub fn put(&mut self, key: String, marker: &ChangeMarker, body: Bytes) {
+        self.entries
+            .retain(|_, rendered| rendered.marker == *marker);
+        if self.entries.len() >= CACHE_MAX_ENTRIES {
+            self.entries.clear();
+        }
+        self.entries.insert(
+            key,
+            Rendered {
+                marker: *marker,
+                rendered_at: Instant::now(),
+                body,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    const MARKER: ChangeMarker = ChangeMarker {
+        generation: 7,
+        modified: 1_500_000_000_500,
+        window: 0,
+    };
+
+    #[test]
+    fn if_none_match() {
+        let req = TestRequest::with_header("if-none-match", "\"6\", \"7\"").finish();
+        assert!(is_not_modified(&req, &MARKER));
+
+        let req = TestRequest::with_header("if-none-match", "W/\"7\"").finish();
+        assert!(is_not_modified(&req, &MARKER));
+
+        let req = TestRequest::with_header("if-none-match", "\"6\"")
+            .header("if-modified-since", "Sun, 01 Jan 2040 00:00:00 GMT")
+            .finish();
+        assert!(!is_not_modified(&req, &MARKER));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        // 1_500_000_000 s
+        let req =
+            TestRequest::with_header("if-modified-since", "Fri, 14 Jul 2017 02:40:00 GMT").finish();
+        assert!(is_not_modified(&req, &MARKER));
+
+        let req =
+            TestRequest::with_header("if-modified-since", "Fri, 14 Jul 2017 02:39:59 GMT").finish();
+        assert!(!is_not_modified(&req, &MARKER));
+
+        assert!(!is_not_modified(&TestRequest::default().finish(), &MARKER));
+    }
+
+    #[test]
+    fn expiring_marker() {
+        let inactive = Some(Duration::from_secs(120));
+        let now = UNIX_EPOCH + Duration::from_secs(1_500_000_100);
+        assert_eq!(MARKER.expiring(None, now), MARKER);
+
+        let marker = MARKER.expiring(inactive, now);
+        let req = TestRequest::with_header("if-none-match", etag(&marker).as_str()).finish();
+        assert!(is_not_modified(&req, &marker));
+
+        // a window later nodes may have expired without a write
+        let later = MARKER.expiring(inactive, now + Duration::from_secs(120));
+        assert!(!is_not_modified(&req, &later));
+        assert!(later.modified > MARKER.modified);
+    }
+
+    #[test]
+    fn render_cache() {
+        let mut cache = RenderCache::default();
+        cache.put("format=csv".into(), &MARKER, Bytes::from_static(b"a,b\n"));
+
+        assert_eq!(
+            cache.get("format=csv", &MARKER),
+            Some(Bytes::from_static(b"a,b\n"))
+        );
+        assert_eq!(cache.get("format=tsv", &MARKER), None);
+
+        let changed = ChangeMarker {
+            generation: 8,
+            ..MARKER
+        };
+        assert_eq!(cache.get("format=csv", &changed), None);
+        assert!(cache.entries.is_empty());
+    }
+}
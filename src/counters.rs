@@ -0,0 +1,396 @@
+// `/v2/nodes` only needs the counter names
+#![cfg_attr(not(feature = "stats_update"), allow(dead_code))]
+
+use actix::prelude::*;
+use futures::future;
+use futures::prelude::*;
+use futures::sync::oneshot;
+use redis_tools::{AsRedisHandle, Redis};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/* bookkeeping of nodes not reporting for this long is read from redis again */
+const FORGET_AFTER_SECS: u64 = 3600;
+
+/// Cumulative counters carried by one report type. They all start from zero
+/// again whenever the Golem client restarts.
+pub struct CounterGroup {
+    pub name: &'static str,
+    pub counters: &'static [&'static str],
+}
+
+pub static STATS: CounterGroup = CounterGroup {
+    name: "stats",
+    counters: &[
+        "completed",
+        "tasks_with_errors",
+        "tasks_with_timeout",
+        "tasks_requested",
+    ],
+};
+
+pub static REQUESTOR_STATS: CounterGroup = CounterGroup {
+    name: "rs",
+    counters: &[
+        "rs_tasks_cnt",
+        "rs_finished_task_cnt",
+        "rs_requested_subtasks_cnt",
+        "rs_collected_results_cnt",
+        "rs_verified_results_cnt",
+        "rs_failed_subtasks_cnt",
+    ],
+};
+
+pub static REQUESTOR_AGGREGATE_STATS: CounterGroup = CounterGroup {
+    name: "requestor",
+    counters: &["requestor_payment_cnt"],
+};
+
+pub static PROVIDER_STATS: CounterGroup = CounterGroup {
+    name: "provider",
+    counters: &[
+        "provider_wtct_cnt",
+        "provider_ttc_cnt",
+        "provider_sra_cnt",
+        "provider_srr_cnt",
+        "provider_income_assigned_sum",
+        "provider_income_completed_sum",
+        "provider_income_paid_sum",
+    ],
+};
+
+/// Every group, see `CounterGroup::track` for the fields stored for them.
+pub static GROUPS: &[&CounterGroup] = &[
+    &STATS,
+    &REQUESTOR_STATS,
+    &REQUESTOR_AGGREGATE_STATS,
+    &PROVIDER_STATS,
+];
+
+fn field(fields: &HashMap<String, String>, key: &str) -> Option<u64> {
+    fields.get(key).and_then(|v| v.parse().ok())
+}
+
+impl CounterGroup {
+    /// Fields to store next to `current`, the freshly reported values, given `previous`,
+    /// the node hash as stored before. For every counter `<c>`:
+    /// `<c>_lifetime` sums it across client restarts, `<c>_base` is the part of it
+    /// counted before the current session and `<c>_per_hour` is its rate since the previous report.
+    /// A restart is assumed when `sessid` changes or any counter of the group decreases.
+    pub fn track(
+        &self,
+        previous: &HashMap<String, String>,
+        current: &HashMap<String, String>,
+        sessid: Option<&str>,
+        at: u64,
+    ) -> HashMap<String, String> {
+        let sessid_key = format!("{}_sessid", self.name);
+        let at_key = format!("{}_at", self.name);
+        let resets_key = format!("{}_resets", self.name);
+
+        let session_changed = match (previous.get(&sessid_key), sessid) {
+            (Some(previous), Some(current)) => previous != current,
+            _ => false,
+        };
+        let decreased = self.counters.iter().any(|counter| {
+            match (field(previous, counter), field(current, counter)) {
+                (Some(previous), Some(current)) => current < previous,
+                _ => false,
+            }
+        });
+        let reset = session_changed || decreased;
+
+        let elapsed = field(previous, &at_key)
+            .filter(|previous_at| *previous_at < at)
+            .map(|previous_at| at - previous_at);
+
+        let mut tracked = HashMap::new();
+        for counter in self.counters {
+            let value = match field(current, counter) {
+                Some(value) => value,
+                None => continue,
+            };
+            let last = field(previous, counter);
+            let mut base = field(previous, &format!("{}_base", counter)).unwrap_or(0);
+            let delta = if reset {
+                base = base.saturating_add(last.unwrap_or(0));
+                Some(value)
+            } else {
+                last.map(|last| value - last)
+            };
+
+            tracked.insert(format!("{}_base", counter), base.to_string());
+            tracked.insert(
+                format!("{}_lifetime", counter),
+                base.saturating_add(value).to_string(),
+            );
+            if let (Some(delta), Some(elapsed)) = (delta, elapsed) {
+                let per_hour = delta as f64 * 3_600_000.0 / elapsed as f64;
+                tracked.insert(format!("{}_per_hour", counter), per_hour.to_string());
+            }
+        }
+
+        let resets = field(previous, &resets_key).unwrap_or(0) + if reset { 1 } else { 0 };
+        tracked.insert(resets_key, resets.to_string());
+        tracked.insert(at_key, at.to_string());
+        if let Some(sessid) = sessid {
+            tracked.insert(sessid_key, sessid.to_string());
+        }
+        tracked
+    }
+
+    /* fields `track` reads back from the stored hash */
+    fn keeps(&self, key: &str) -> bool {
+        let group_field = key.starts_with(self.name)
+            && matches!(&key[self.name.len()..], "_sessid" | "_at" | "_resets");
+        group_field
+            || self.counters.iter().any(|counter| {
+                key.starts_with(counter) && matches!(&key[counter.len()..], "" | "_base")
+            })
+    }
+
+    fn state(&self, fields: &HashMap<String, String>) -> HashMap<String, String> {
+        fields
+            .iter()
+            .filter(|(key, _)| self.keeps(key))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+}
+
+/// Tracks the counters of every report, one report of a node after another.
+/// The stored hash of a node is only read for its first report; later ones continue from
+/// the bookkeeping kept here, so concurrent reports never start from the same stale hash.
+pub struct Tracker {
+    redis: Redis,
+    known: HashMap<NodeKey, Known>,
+    /// reports waiting for the stored hash of their node
+    waiting: HashMap<NodeKey, Vec<(Track, oneshot::Sender<Tracked>)>>,
+}
+
+/* counter group name and node id */
+type NodeKey = (&'static str, String);
+
+struct Known {
+    state: HashMap<String, String>,
+    used: Instant,
+}
+
+type Tracked = Result<HashMap<String, String>, String>;
+
+impl Tracker {
+    pub fn new(redis: Redis) -> Self {
+        Tracker {
+            redis,
+            known: HashMap::new(),
+            waiting: HashMap::new(),
+        }
+    }
+
+    fn track(&mut self, key: &NodeKey, msg: &Track) -> HashMap<String, String> {
+        let known = self.known.entry(key.clone()).or_insert_with(|| Known {
+            state: HashMap::new(),
+            used: Instant::now(),
+        });
+        let tracked = msg
+            .group
+            .track(&known.state, &msg.current, msg.sessid.as_deref(), msg.at);
+        known.state.extend(msg.group.state(&msg.current));
+        known.state.extend(msg.group.state(&tracked));
+        known.used = Instant::now();
+        tracked
+    }
+
+    fn read(&mut self, key: NodeKey, ctx: &mut Context<Self>) {
+        let f = self
+            .redis
+            .as_redis_handle()
+            .get_hash(format!("nodeinfo.{}", key.1))
+            .into_actor(self)
+            .then(move |r, act, _| {
+                let waiting = act.waiting.remove(&key).unwrap_or_default();
+                match r {
+                    Ok(previous) => {
+                        if let Some((msg, _)) = waiting.first() {
+                            act.known.insert(
+                                key.clone(),
+                                Known {
+                                    state: msg.group.state(&previous),
+                                    used: Instant::now(),
+                                },
+                            );
+                        }
+                        for (msg, tx) in waiting {
+                            let _ = tx.send(Ok(act.track(&key, &msg)));
+                        }
+                    }
+                    Err(e) => {
+                        for (_, tx) in waiting {
+                            let _ = tx.send(Err(e.to_string()));
+                        }
+                    }
+                }
+                ::actix::fut::ok(())
+            });
+        ctx.spawn(f);
+    }
+
+    fn forget(&mut self, ctx: &mut Context<Self>) {
+        let forget_after = Duration::from_secs(FORGET_AFTER_SECS);
+        self.known
+            .retain(|_, known| known.used.elapsed() < forget_after);
+        ctx.run_later(forget_after, |act, ctx| act.forget(ctx));
+    }
+}
+
+impl Actor for Tracker {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_later(Duration::from_secs(FORGET_AFTER_SECS), |act, ctx| {
+            act.forget(ctx)
+        });
+    }
+}
+
+/// A report's counters, answered with the fields to store next to them, see `CounterGroup::track`.
+pub struct Track {
+    pub group: &'static CounterGroup,
+    pub cliid: String,
+    pub current: HashMap<String, String>,
+    pub sessid: Option<String>,
+    pub at: u64,
+}
+
+impl Message for Track {
+    type Result = Tracked;
+}
+
+impl Handler<Track> for Tracker {
+    type Result = ResponseFuture<HashMap<String, String>, String>;
+
+    fn handle(&mut self, msg: Track, ctx: &mut Self::Context) -> Self::Result {
+        let key = (msg.group.name, msg.cliid.clone());
+        if self.known.contains_key(&key) {
+            return Box::new(future::ok(self.track(&key, &msg)));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let first = !self.waiting.contains_key(&key);
+        self.waiting.entry(key.clone()).or_default().push((msg, tx));
+        if first {
+            self.read(key, ctx);
+        }
+        Box::new(
+            rx.map_err(|_| "counter tracker gone".to_string())
+                .and_then(|r| r),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn first_report() {
+        let tracked = STATS.track(
+            &HashMap::new(),
+            &fields(&[("completed", "5")]),
+            Some("s1"),
+            1000,
+        );
+        assert_eq!(tracked["completed_lifetime"], "5");
+        assert_eq!(tracked["stats_resets"], "0");
+        assert_eq!(tracked["stats_sessid"], "s1");
+        assert!(!tracked.contains_key("completed_per_hour"));
+    }
+
+    #[test]
+    fn rate_between_reports() {
+        let previous = fields(&[
+            ("completed", "5"),
+            ("completed_base", "0"),
+            ("stats_at", "0"),
+            ("stats_sessid", "s1"),
+        ]);
+        let tracked = STATS.track(
+            &previous,
+            &fields(&[("completed", "8")]),
+            Some("s1"),
+            1_800_000,
+        );
+        assert_eq!(tracked["completed_lifetime"], "8");
+        assert_eq!(tracked["completed_per_hour"], "6");
+        assert_eq!(tracked["stats_resets"], "0");
+    }
+
+    #[test]
+    fn reset_keeps_lifetime_total() {
+        let previous = fields(&[
+            ("completed", "5"),
+            ("completed_base", "10"),
+            ("stats_at", "0"),
+            ("stats_sessid", "s1"),
+            ("stats_resets", "1"),
+        ]);
+
+        // counter went down
+        let tracked = STATS.track(&previous, &fields(&[("completed", "2")]), None, 3_600_000);
+        assert_eq!(tracked["completed_base"], "15");
+        assert_eq!(tracked["completed_lifetime"], "17");
+        assert_eq!(tracked["completed_per_hour"], "2");
+        assert_eq!(tracked["stats_resets"], "2");
+
+        // new session, counter happens to be higher already
+        let tracked = STATS.track(
+            &previous,
+            &fields(&[("completed", "7")]),
+            Some("s2"),
+            3_600_000,
+        );
+        assert_eq!(tracked["completed_lifetime"], "22");
+        assert_eq!(tracked["stats_resets"], "2");
+        assert_eq!(tracked["stats_sessid"], "s2");
+    }
+
+    #[test]
+    fn saturated_lifetime() {
+        let max = u64::MAX.to_string();
+        let previous = fields(&[("completed", "5"), ("completed_base", &max)]);
+        let tracked = STATS.track(&previous, &fields(&[("completed", "2")]), None, 1000);
+        assert_eq!(tracked["completed_base"], max);
+        assert_eq!(tracked["completed_lifetime"], max);
+    }
+
+    #[test]
+    fn bookkeeping_state() {
+        let stored = fields(&[
+            ("completed", "5"),
+            ("completed_base", "10"),
+            ("completed_lifetime", "15"),
+            ("stats_at", "0"),
+            ("stats_sessid", "s1"),
+            ("rs_tasks_cnt", "3"),
+            ("num_cores", "4"),
+        ]);
+        let mut state: Vec<_> = STATS.state(&stored).into_iter().collect();
+        state.sort();
+        assert_eq!(
+            state,
+            vec![
+                ("completed".to_string(), "5".to_string()),
+                ("completed_base".to_string(), "10".to_string()),
+                ("stats_at".to_string(), "0".to_string()),
+                ("stats_sessid".to_string(), "s1".to_string()),
+            ]
+        );
+    }
+}
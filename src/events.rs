@@ -0,0 +1,360 @@
+use actix::prelude::*;
+use actix_web::http::{header, ContentEncoding};
+use actix_web::{self, ws, App, HttpMessage, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures::prelude::*;
+use futures::sync::mpsc;
+use openapi::{self, ApiDoc};
+use schemars::JsonSchema;
+use serde_json;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::form_urlencoded::parse;
+
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/* a subscriber this many items behind is disconnected */
+const SUBSCRIBER_BUFFER: usize = 256;
+
+#[derive(Serialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Update,
+    Login,
+    Logout,
+    Expiry,
+}
+
+impl EventKind {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "update" => Some(EventKind::Update),
+            "login" => Some(EventKind::Login),
+            "logout" => Some(EventKind::Logout),
+            "expiry" => Some(EventKind::Expiry),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            EventKind::Update => "update",
+            EventKind::Login => "login",
+            EventKind::Logout => "logout",
+            EventKind::Expiry => "expiry",
+        }
+    }
+}
+
+/// Compact notification about a change of a single node.
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+pub struct NodeEvent {
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    pub cliid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub net: Option<String>,
+    pub timestamp: u64,
+}
+
+impl NodeEvent {
+    pub fn new(kind: EventKind, cliid: String, net: Option<String>) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+        NodeEvent {
+            kind,
+            cliid,
+            net,
+            timestamp: now.as_secs() * 1000 + u64::from(now.subsec_millis()),
+        }
+    }
+}
+
+impl Message for NodeEvent {
+    type Result = ();
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventFilter {
+    kinds: Option<Vec<EventKind>>,
+    net: Option<String>,
+}
+
+impl EventFilter {
+    /* e.g. `types=login,logout&net=testnet` */
+    fn from_query(query: &str) -> Self {
+        let mut filter = EventFilter::default();
+
+        for (k, v) in parse(query.as_bytes()) {
+            match k.as_ref() {
+                "types" => filter.kinds = Some(v.split(',').filter_map(EventKind::parse).collect()),
+                "net" => filter.net = Some(v.into_owned()),
+                _ => debug!("unknown events param {}={}", k, v),
+            }
+        }
+        filter
+    }
+
+    fn matches(&self, event: &NodeEvent) -> bool {
+        let kind_ok = match self.kinds {
+            Some(ref kinds) => kinds.contains(&event.kind),
+            None => true,
+        };
+        let net_ok = match self.net {
+            Some(ref net) => event.net.as_ref() == Some(net),
+            None => true,
+        };
+        kind_ok && net_ok
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum FeedItem {
+    Event(NodeEvent),
+    KeepAlive,
+}
+
+impl FeedItem {
+    fn to_sse(&self) -> Bytes {
+        match self {
+            FeedItem::Event(event) => Bytes::from(format!(
+                "event: {}\ndata: {}\n\n",
+                event.kind.name(),
+                serde_json::to_string(event).unwrap_or_default()
+            )),
+            FeedItem::KeepAlive => Bytes::from_static(b":\n\n"),
+        }
+    }
+}
+
+pub struct Subscribe {
+    filter: EventFilter,
+    tx: mpsc::Sender<FeedItem>,
+}
+
+impl Message for Subscribe {
+    type Result = ();
+}
+
+struct Subscriber {
+    filter: EventFilter,
+    tx: mpsc::Sender<FeedItem>,
+}
+
+/// Fans node events out to `/v1/events` listeners. Shared by all server workers.
+#[derive(Default)]
+pub struct EventHub {
+    subscribers: Vec<Subscriber>,
+    /* only logins carry `net`, remember it for the rest of the node's reports */
+    nets: HashMap<String, String>,
+}
+
+impl Actor for EventHub {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_later(KEEP_ALIVE_INTERVAL, |act, ctx| act.keep_alive(ctx));
+    }
+}
+
+impl EventHub {
+    fn keep_alive(&mut self, ctx: &mut Context<Self>) {
+        self.subscribers
+            .retain_mut(|s| s.tx.try_send(FeedItem::KeepAlive).is_ok());
+        ctx.run_later(KEEP_ALIVE_INTERVAL, |act, ctx| act.keep_alive(ctx));
+    }
+}
+
+impl Handler<Subscribe> for EventHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Self::Context) {
+        self.subscribers.push(Subscriber {
+            filter: msg.filter,
+            tx: msg.tx,
+        });
+        debug!("events subscribers: {}", self.subscribers.len());
+    }
+}
+
+impl Handler<NodeEvent> for EventHub {
+    type Result = ();
+
+    fn handle(&mut self, mut event: NodeEvent, _: &mut Self::Context) {
+        match (event.kind, event.net.clone()) {
+            (EventKind::Login, Some(net)) => {
+                self.nets.insert(event.cliid.clone(), net);
+            }
+            (EventKind::Logout, _) | (EventKind::Expiry, _) => {
+                if event.net.is_none() {
+                    event.net = self.nets.remove(&event.cliid);
+                } else {
+                    self.nets.remove(&event.cliid);
+                }
+            }
+            (_, None) => event.net = self.nets.get(&event.cliid).cloned(),
+            _ => (),
+        }
+
+        self.subscribers.retain_mut(|s| {
+            !s.filter.matches(&event) || s.tx.try_send(FeedItem::Event(event.clone())).is_ok()
+        });
+    }
+}
+
+struct EventsSocket {
+    hub: Addr<Syn, EventHub>,
+    filter: EventFilter,
+}
+
+impl Actor for EventsSocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+        self.hub.do_send(Subscribe {
+            filter: self.filter.clone(),
+            tx,
+        });
+        ctx.add_stream(rx);
+    }
+}
+
+impl StreamHandler<FeedItem, ()> for EventsSocket {
+    fn handle(&mut self, item: FeedItem, ctx: &mut Self::Context) {
+        match item {
+            FeedItem::Event(event) => match serde_json::to_string(&event) {
+                Ok(json) => ctx.text(json),
+                Err(e) => warn!("event serialization: {}", e),
+            },
+            FeedItem::KeepAlive => ctx.ping(""),
+        }
+    }
+}
+
+impl StreamHandler<ws::Message, ws::ProtocolError> for EventsSocket {
+    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        match msg {
+            ws::Message::Ping(msg) => ctx.pong(&msg),
+            ws::Message::Close(_) => ctx.stop(),
+            _ => (),
+        }
+    }
+}
+
+fn is_websocket(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false)
+}
+
+fn events(hub: &Addr<Syn, EventHub>, req: HttpRequest) -> Result<HttpResponse, actix_web::Error> {
+    let filter = EventFilter::from_query(req.query_string());
+
+    if is_websocket(&req) {
+        return ws::start(
+            req,
+            EventsSocket {
+                hub: hub.clone(),
+                filter,
+            },
+        );
+    }
+
+    let (tx, rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+    hub.do_send(Subscribe { filter, tx });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .content_encoding(ContentEncoding::Identity)
+        .header("cache-control", "no-cache")
+        .streaming(
+            rx.map(|item| item.to_sse())
+                .map_err(|_| actix_web::error::ErrorInternalServerError("event hub closed")),
+        ))
+}
+
+pub fn route_events(hub: Addr<Syn, EventHub>) -> impl Fn(App) -> App {
+    move |app: App| {
+        let hub = hub.clone();
+        app.resource("/v1/events", move |r| {
+            r.get().f(move |req| events(&hub, req))
+        })
+    }
+}
+
+/// `/v1/events` in the OpenAPI document.
+pub fn describe(doc: &mut ApiDoc) {
+    let event = doc.schema::<NodeEvent>();
+
+    doc.add(
+        "/v1/events",
+        "get",
+        json!({
+            "summary": "live feed of node events, as server-sent events or websocket messages",
+            "parameters": [
+                {
+                    "name": "types",
+                    "in": "query",
+                    "description": "comma separated event types",
+                    "schema": { "type": "string" }
+                },
+                { "name": "net", "in": "query", "schema": { "type": "string" } }
+            ],
+            "responses": {
+                "200": openapi::response("one `data:` line per event", "text/event-stream", event)
+            }
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_filter() {
+        let filter = EventFilter::from_query("types=login,logout,bogus&net=testnet");
+        assert_eq!(
+            filter,
+            EventFilter {
+                kinds: Some(vec![EventKind::Login, EventKind::Logout]),
+                net: Some("testnet".into()),
+            }
+        );
+        assert_eq!(EventFilter::from_query(""), EventFilter::default());
+    }
+
+    #[test]
+    fn filter_matches() {
+        let login = NodeEvent::new(EventKind::Login, "n1".into(), Some("mainnet".into()));
+        let update = NodeEvent::new(EventKind::Update, "n1".into(), None);
+
+        assert!(EventFilter::default().matches(&login));
+        assert!(EventFilter::default().matches(&update));
+
+        let filter = EventFilter::from_query("types=login&net=mainnet");
+        assert!(filter.matches(&login));
+        assert!(!filter.matches(&update));
+
+        let filter = EventFilter::from_query("net=testnet");
+        assert!(!filter.matches(&login));
+    }
+
+    #[test]
+    fn sse_framing() {
+        let item = FeedItem::Event(NodeEvent {
+            kind: EventKind::Expiry,
+            cliid: "n1".into(),
+            net: None,
+            timestamp: 7,
+        });
+        assert_eq!(
+            item.to_sse(),
+            Bytes::from(
+                "event: expiry\ndata: {\"type\":\"expiry\",\"cliid\":\"n1\",\"timestamp\":7}\n\n"
+            )
+        );
+    }
+}
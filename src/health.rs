@@ -0,0 +1,287 @@
+use actix_web::{App, HttpResponse};
+use openapi::{self, ApiDoc};
+use schemars::JsonSchema;
+use MonitorSettings;
+
+#[cfg(feature = "redis")]
+use actix::prelude::*;
+#[cfg(feature = "redis")]
+use actix_web::{AsyncResponder, FutureResponse};
+#[cfg(feature = "redis")]
+use futures::future;
+#[cfg(feature = "redis")]
+use futures::prelude::*;
+#[cfg(feature = "redis")]
+use redis_tools::{AsRedisHandle, Redis};
+#[cfg(feature = "redis")]
+use replica::ReplicaStatus;
+#[cfg(feature = "redis")]
+use std::cell::RefCell;
+#[cfg(feature = "redis")]
+use std::rc::Rc;
+#[cfg(feature = "redis")]
+use std::time::Duration;
+#[cfg(feature = "redis")]
+use updater::{Alive, QueueDepth, Replication, Updater};
+
+/// Cargo features this binary was built with.
+static FEATURES: &[(&str, bool)] = &[
+    ("stats_update", cfg!(feature = "stats_update")),
+    ("list_nodes", cfg!(feature = "list_nodes")),
+    ("pingme", cfg!(feature = "pingme")),
+];
+
+#[derive(Serialize, JsonSchema, Debug)]
+struct VersionInfo {
+    version: &'static str,
+    features: Vec<&'static str>,
+    /// commit the binary was built from, `unknown` outside of a git checkout
+    git_hash: &'static str,
+}
+
+impl VersionInfo {
+    fn current() -> Self {
+        VersionInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            features: FEATURES
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(feature, _)| *feature)
+                .collect(),
+            git_hash: env!("GIT_HASH"),
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+#[derive(Serialize, JsonSchema, Debug)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<f64>,
+    /// writes held by the updater, not ready while its queue is full
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue: Option<QueueDepth>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[cfg(feature = "redis")]
+#[derive(Serialize, JsonSchema, Debug)]
+struct Readiness {
+    ready: bool,
+    redis: Check,
+    /// one per updater of the http worker that answered
+    updaters: Vec<Check>,
+    /// writes copied to other redis instances, not taken into account for `ready`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    replicas: Vec<ReplicaStatus>,
+}
+
+/// Updaters started by the routes of one http worker, all of them checked by `/readyz`.
+#[cfg(feature = "redis")]
+#[derive(Clone, Default)]
+pub struct WorkerUpdaters(Rc<RefCell<Vec<Addr<Unsync, Updater>>>>);
+
+#[cfg(feature = "redis")]
+impl WorkerUpdaters {
+    pub fn add(&self, updater: Addr<Unsync, Updater>) {
+        self.0.borrow_mut().push(updater);
+    }
+
+    fn all(&self) -> Vec<Addr<Unsync, Updater>> {
+        self.0.borrow().clone()
+    }
+}
+
+#[cfg(not(feature = "redis"))]
+#[derive(Clone, Default)]
+pub struct WorkerUpdaters;
+
+/// `/healthz`, `/version` and, when built with redis, `/readyz` checking the primary redis
+/// and `updaters`.
+pub fn route_health(settings: &MonitorSettings, updaters: WorkerUpdaters) -> impl Fn(App) -> App {
+    let route_readiness = route_readiness(settings, updaters);
+
+    move |app: App| {
+        app.configure(&route_readiness)
+            .resource("/healthz", |r| {
+                r.get().f(|_| {
+                    HttpResponse::Ok()
+                        .header("cache-control", "no-cache")
+                        .content_type("text/plain")
+                        .body("ok")
+                })
+            })
+            .resource("/version", |r| {
+                r.get()
+                    .f(|_| HttpResponse::Ok().json(VersionInfo::current()))
+            })
+    }
+}
+
+#[cfg(feature = "redis")]
+fn route_readiness(settings: &MonitorSettings, updaters: WorkerUpdaters) -> impl Fn(App) -> App {
+    let redis_address = settings.redis.clone();
+
+    move |app: App| {
+        let redis = Redis::connect(&redis_address);
+        let updaters = updaters.clone();
+
+        app.resource("/readyz", move |r| {
+            r.get().f(move |_| readiness(&redis, &updaters.all()))
+        })
+    }
+}
+
+#[cfg(not(feature = "redis"))]
+fn route_readiness(_: &MonitorSettings, _: WorkerUpdaters) -> impl Fn(App) -> App {
+    |app| app
+}
+
+/// Ready when `redis` answers a `PING` and every one of `updaters` is processing its mailbox.
+#[cfg(feature = "redis")]
+fn readiness(redis: &Redis, updaters: &[Addr<Unsync, Updater>]) -> FutureResponse<HttpResponse> {
+    let redis = redis.as_redis_handle().ping().then(|r| {
+        Ok::<_, ::actix_web::Error>(match r {
+            Ok(latency) => Check {
+                ok: true,
+                latency_ms: Some(
+                    latency.as_secs() as f64 * 1000.0 + latency.subsec_nanos() as f64 / 1e6,
+                ),
+                queue: None,
+                error: None,
+            },
+            Err(e) => Check {
+                ok: false,
+                latency_ms: None,
+                queue: None,
+                error: Some(e.to_string()),
+            },
+        })
+    });
+    // the replicas are shared, any updater reports them all
+    let replicas = match updaters.first() {
+        Some(updater) => future::Either::A(
+            updater
+                .send(Replication)
+                .timeout(Duration::from_secs(2))
+                .then(|r| {
+                    Ok::<_, ::actix_web::Error>(r.ok().and_then(|r| r.ok()).unwrap_or_default())
+                }),
+        ),
+        None => future::Either::B(future::ok(Vec::new())),
+    };
+    let updaters: Vec<_> = updaters.iter().map(check_updater).collect();
+    let updaters = future::join_all(updaters);
+
+    redis
+        .join3(updaters, replicas)
+        .map(|(redis, updaters, replicas)| {
+            let ready = redis.ok && updaters.iter().all(|updater| updater.ok);
+            if !ready {
+                warn!("not ready: redis={:?} updaters={:?}", redis, updaters);
+            }
+            let mut builder = if ready {
+                HttpResponse::Ok()
+            } else {
+                HttpResponse::ServiceUnavailable()
+            };
+            builder.header("cache-control", "no-cache").json(Readiness {
+                ready,
+                redis,
+                updaters,
+                replicas,
+            })
+        })
+        .responder()
+}
+
+/// Passes while `updater` answers within 2 s and its queue has room.
+#[cfg(feature = "redis")]
+fn check_updater(
+    updater: &Addr<Unsync, Updater>,
+) -> impl Future<Item = Check, Error = ::actix_web::Error> {
+    updater
+        .send(Alive)
+        .timeout(Duration::from_secs(2))
+        .then(|r| {
+            Ok::<_, ::actix_web::Error>(match r {
+                Ok(queue) => Check {
+                    ok: !queue.is_full(),
+                    latency_ms: None,
+                    queue: Some(queue),
+                    error: if queue.is_full() {
+                        Some("write queue full".into())
+                    } else {
+                        None
+                    },
+                },
+                Err(e) => Check {
+                    ok: false,
+                    latency_ms: None,
+                    queue: None,
+                    error: Some(e.to_string()),
+                },
+            })
+        })
+}
+
+/// `/healthz`, `/readyz` and `/version` in the OpenAPI document.
+pub fn describe(doc: &mut ApiDoc) {
+    let version = doc.schema::<VersionInfo>();
+
+    doc.add(
+        "/healthz",
+        "get",
+        json!({
+            "summary": "the process is up",
+            "responses": {
+                "200": openapi::response("ok", "text/plain", json!({ "type": "string" }))
+            }
+        }),
+    );
+    doc.add(
+        "/version",
+        "get",
+        json!({
+            "summary": "build information",
+            "responses": { "200": openapi::response("build", "application/json", version) }
+        }),
+    );
+
+    #[cfg(feature = "redis")]
+    describe_readiness(doc);
+}
+
+#[cfg(feature = "redis")]
+fn describe_readiness(doc: &mut ApiDoc) {
+    let readiness = doc.schema::<Readiness>();
+    doc.add(
+        "/readyz",
+        "get",
+        json!({
+            "summary": "redis answers and updates are being written",
+            "responses": {
+                "200": openapi::response("ready", "application/json", readiness.clone()),
+                "503": openapi::response("not ready", "application/json", readiness)
+            }
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_info() {
+        let info = VersionInfo::current();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            info.features.contains(&"stats_update"),
+            cfg!(feature = "stats_update")
+        );
+        assert!(!info.git_hash.is_empty());
+    }
+}
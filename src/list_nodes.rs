@@ -1,184 +1,787 @@
+use super::MonitorSettings;
 use actix::prelude::*;
-use actix_redis::RedisActor;
+use actix_web::dev::HttpResponseBuilder;
 use actix_web::http::header::LastModified;
-use actix_web::{self, http, App, HttpRequest, HttpResponse};
+use actix_web::http::ContentEncoding;
+use actix_web::{self, http, App, AsyncResponder, HttpRequest, HttpResponse};
+use conditional::{is_not_modified, not_modified, with_validators, RenderCache};
+use events::{EventHub, EventKind, NodeEvent};
 use futures::future;
 use futures::prelude::*;
+use node_view::{self, NodeView};
+use openapi::{self, ApiDoc};
 use redis_tools::*;
-use std::collections::HashMap;
+use schemars::JsonSchema;
+use serde::{Serialize, Serializer};
+use serde_json;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::rc::Rc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use stream_utils::collect_on_complete;
+use url::form_urlencoded::parse;
+use versions::{VersionPolicy, VersionReport};
 
 pub fn route_list_nodes(
-    redis_address: String,
-    remove_inactive_after: Option<Duration>,
+    settings: &MonitorSettings,
+    events: Addr<Syn, EventHub>,
 ) -> impl Fn(App) -> App {
+    let redis_address = settings.redis.clone();
+    let remove_inactive_after = settings.inactive.map(Duration::from_secs);
+    let dump_columns = settings.dump_columns();
+    let version_policy = VersionPolicy::new(
+        settings.min_version.as_deref(),
+        settings.min_protocol_version,
+    )
+    .unwrap_or_else(|e| panic!("{}", e));
+
     move |app: App| {
-        use actix_redis::RedisActor;
-        let redis_actor = RedisActor::start(redis_address.clone());
+        let redis_actor = Redis::connect(&redis_address);
         let redis_actor_j = redis_actor.clone();
+        let redis_actor_s = redis_actor.clone();
+        let redis_actor_v = redis_actor.clone();
+        let redis_actor_t = redis_actor.clone();
+        let version_policy = version_policy.clone();
+        let events_t = events.clone();
+        let events = events.clone();
 
-        let csv_header = bytes::Bytes::from(CSV_FIELDS.join(",") + "\n");
+        let default_columns = Rc::new(dump_columns.clone().unwrap_or_else(legacy_columns));
+        let dump_cache = Rc::new(RefCell::new(RenderCache::default()));
+        let nodes_cache = Rc::new(RefCell::new(RenderCache::default()));
+        let nodes_cache_t = nodes_cache.clone();
 
         app.resource("/dump", move |r| {
-            r.get().with(move |_: HttpRequest<_>| {
-                let redis_iter = redis_actor.clone();
-                let generated = LastModified(SystemTime::now().into());
+            r.get().with(move |req: HttpRequest<_>| {
+                let params = match DumpParams::from_query(req.query_string()) {
+                    Ok(params) => params,
+                    Err(e) => {
+                        return future::err::<HttpResponse, _>(actix_web::error::ErrorBadRequest(e))
+                            .responder()
+                    }
+                };
+                let format = params.format;
+                let encoding = params.encoding;
+                let redis = redis_actor.clone();
+                let default_columns = default_columns.clone();
+                let cache = dump_cache.clone();
+                let cache_key = req.query_string().to_string();
 
-                let csv_framed = redis_actor
-                    .as_redis_handle()
-                    .scan("nodeinfo.*".into(), 10)
-                    .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))
-                    .map(move |key_chunk| dump_csv_for_keys(&redis_iter, key_chunk))
-                    .buffer_unordered(2);
-
-                let header = csv_header.clone();
-                let cvs_framed_with_header = futures::stream::once(Ok(header)).chain(csv_framed);
-
-                Ok::<_, actix_web::Error>(
-                    HttpResponse::Ok()
-                        .content_type("text/x-csv")
-                        .header("cache-control", "public, max-age=30")
-                        .header(http::header::LAST_MODIFIED, generated)
-                        .header(
-                            "content-disposition",
-                            "attachment; filename=\"golem-stats.csv\"",
-                        )
-                        .write_buffer_capacity(10240)
-                        .streaming(cvs_framed_with_header),
-                )
+                change_marker(&redis_actor, remove_inactive_after)
+                    .and_then(move |marker| {
+                        if let Some(ref marker) = marker {
+                            if is_not_modified(&req, marker) {
+                                return future::Either::A(future::ok(not_modified(marker)));
+                            }
+                            if let Some(body) = cache.borrow_mut().get(&cache_key, marker) {
+                                return future::Either::A(future::ok(
+                                    dump_response(format, encoding, Some(marker)).body(body),
+                                ));
+                            }
+                        }
+
+                        let columns = match (format.delimiter(), params.columns) {
+                            (None, Columns::Listed(columns)) => {
+                                future::Either::A(future::ok(Some(Rc::new(columns))))
+                            }
+                            (None, _) => future::Either::A(future::ok(None)),
+                            (Some(_), Columns::Listed(columns)) => {
+                                future::Either::A(future::ok(Some(Rc::new(columns))))
+                            }
+                            (Some(_), Columns::Legacy) => {
+                                future::Either::A(future::ok(Some(Rc::new(legacy_columns()))))
+                            }
+                            (Some(_), Columns::Default) => {
+                                future::Either::A(future::ok(Some(default_columns)))
+                            }
+                            (Some(_), Columns::All) => future::Either::B(
+                                all_fields(&redis).map(|fields| Some(Rc::new(fields))),
+                            ),
+                        };
+
+                        future::Either::B(columns.map(move |columns: Option<Rc<Vec<String>>>| {
+                            let header = format.header(columns.as_ref().map(|c| c.as_slice()));
+                            let redis_iter = redis.clone();
+
+                            let framed = redis
+                                .as_redis_handle()
+                                .scan("nodeinfo.*".into(), 10)
+                                .map_err(|e| {
+                                    actix_web::error::ErrorInternalServerError(e.to_string())
+                                })
+                                .map(move |key_chunk| {
+                                    dump_for_keys(&redis_iter, key_chunk, format, columns.clone())
+                                })
+                                .buffer_unordered(2);
+
+                            let framed_with_header = collect_on_complete(
+                                futures::stream::iter_ok(header).chain(framed),
+                                store_rendered(cache, cache_key, marker),
+                            );
+
+                            dump_response(format, encoding, marker.as_ref())
+                                .write_buffer_capacity(10240)
+                                .streaming(framed_with_header)
+                        }))
+                    })
+                    .responder()
             })
         })
         .resource("/v1/nodes", move |r| {
-            r.get().with(move |_: HttpRequest<_>| {
+            r.get().with(move |req: HttpRequest<_>| {
                 let redis = redis_actor_j.clone();
-                let redis_rem = redis_actor_j.clone();
-                let remove_inactive_after = remove_inactive_after.clone();
-                let now = SystemTime::now();
+                let events = events.clone();
+                let cache = nodes_cache.clone();
 
-                let json = redis_actor_j
-                    .as_redis_handle()
-                    .scan_set("active_nodes".into(), 10)
-                    .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))
-                    .map(move |chunk| {
-                        let redis = redis.clone();
-                        futures::stream::iter_ok(
-                            chunk
-                                .into_iter()
-                                .map(move |node_id| {
-                                    redis
-                                        .as_redis_handle()
-                                        .get_hash(format!("nodeinfo.{}", node_id))
-                                        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))
-                                        .and_then(|mut node| {
-                                            if let Some(ip_value) = node.get_mut("ip") {
-                                                *ip_value = obfuscate_ip(ip_value.to_string())
-                                            }
-                                            Ok((node_id, node))
-                                        })
-                                })
-                                .into_iter()
-                        )
+                change_marker(&redis_actor_j, remove_inactive_after)
+                    .map(move |marker| {
+                        if let Some(ref marker) = marker {
+                            if is_not_modified(&req, marker) {
+                                return not_modified(marker);
+                            }
+                            if let Some(body) = cache.borrow_mut().get("", marker) {
+                                return nodes_response(Some(marker)).body(body);
+                            }
+                        }
+
+                        let json = collect_on_complete(
+                            active_nodes_json(&redis, remove_inactive_after, &events, decode_hash),
+                            store_rendered(cache, String::new(), marker),
+                        );
+                        nodes_response(marker.as_ref()).streaming(json)
                     })
-                    .flatten()
-                    .map(move |node_fut| {
-                        let redis_rem = redis_rem.clone();
-                        if let Some(timeout) = remove_inactive_after {
-                            future::Either::B(node_fut.and_then(move |(node_id, node)| {
-                                if let Some(ts) = node.get("timestamp").and_then(|s| s.parse().ok()) {
-                                    let ts = UNIX_EPOCH + Duration::from_millis(ts) + timeout;
-                                    if ts < now {
-                                        debug!("removing: ts={:?} now={:?} tmo={:?}", ts, now, timeout);
-                                        future::Either::B(
-                                            redis_rem
-                                                .as_redis_handle()
-                                                .remove_from_set("active_nodes".into(), node_id)
-                                                .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))
-                                                .and_then(|_| Ok(node)))
-                                    }
-                                    else {
-                                        future::Either::A(future::ok(node))
-                                    }
+                    .responder()
+            })
+        })
+        .resource("/v2/nodes", move |r| {
+            r.get().with(move |req: HttpRequest<_>| {
+                let redis = redis_actor_t.clone();
+                let events = events_t.clone();
+                let cache = nodes_cache_t.clone();
+
+                change_marker(&redis_actor_t, remove_inactive_after)
+                    .map(move |marker| {
+                        if let Some(ref marker) = marker {
+                            if is_not_modified(&req, marker) {
+                                return not_modified(marker);
+                            }
+                            if let Some(body) = cache.borrow_mut().get("v2", marker) {
+                                return nodes_response(Some(marker)).body(body);
+                            }
+                        }
+
+                        let json = collect_on_complete(
+                            active_nodes_json(
+                                &redis,
+                                remove_inactive_after,
+                                &events,
+                                NodeView::from_hash,
+                            ),
+                            store_rendered(cache, "v2".into(), marker),
+                        );
+                        nodes_response(marker.as_ref()).streaming(json)
+                    })
+                    .responder()
+            })
+        })
+        .resource("/v2/schema", |r| {
+            r.get().f(|_| {
+                HttpResponse::Ok()
+                    .content_type("application/schema+json")
+                    .header("cache-control", "public, max-age=3600")
+                    .body(node_view::schema().to_string())
+            })
+        })
+        .resource("/v1/clock-skew", move |r| {
+            r.get().with(move |_: HttpRequest<_>| {
+                active_node_hashes(&redis_actor_s, remove_inactive_after)
+                    .fold(
+                        ClockSkewDistribution::new(),
+                        move |mut distribution, nodes| {
+                            for node in nodes {
+                                if let Some(skew) =
+                                    node.get("clock_skew").and_then(|s| s.parse().ok())
+                                {
+                                    let skewed = node
+                                        .get("clock_skewed")
+                                        .map(|s| s == "true")
+                                        .unwrap_or(false);
+                                    distribution.add(skew, skewed);
                                 }
-                                else {
-                                    future::Either::A(future::ok(node))
+                            }
+                            Ok::<_, actix_redis::RespError>(distribution)
+                        },
+                    )
+                    .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))
+                    .map(|distribution| {
+                        HttpResponse::Ok()
+                            .header("cache-control", "public, max-age=30")
+                            .json(distribution)
+                    })
+                    .responder()
+            })
+        })
+        .resource("/v1/versions", move |r| {
+            r.get().with(move |_: HttpRequest<_>| {
+                active_node_hashes(&redis_actor_v, remove_inactive_after)
+                    .fold(VersionReport::new(&version_policy), |mut report, nodes| {
+                        for node in nodes {
+                            report.add(&node);
+                        }
+                        Ok::<_, actix_redis::RespError>(report)
+                    })
+                    .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))
+                    .map(|report| {
+                        HttpResponse::Ok()
+                            .header("cache-control", "public, max-age=30")
+                            .json(report.finish())
+                    })
+                    .responder()
+            })
+        })
+    }
+}
+
+/* chunks of stored active nodes, skipping the ones not seen for `remove_inactive_after` */
+fn active_node_hashes(
+    redis_actor: &Redis,
+    remove_inactive_after: Option<Duration>,
+) -> impl Stream<Item = Vec<HashMap<String, String>>, Error = actix_redis::RespError> {
+    let redis = redis_actor.clone();
+    let active_since = remove_inactive_after.map(|timeout| SystemTime::now() - timeout);
+
+    redis_actor
+        .as_redis_handle()
+        .scan_set("active_nodes".into(), 50)
+        .map(move |chunk| {
+            future::join_all(
+                chunk
+                    .into_iter()
+                    .map(|node_id| {
+                        redis
+                            .as_redis_handle()
+                            .get_hash(format!("nodeinfo.{}", node_id))
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .buffered(2)
+        .map(move |nodes| {
+            nodes
+                .into_iter()
+                .filter(|node| {
+                    let last_seen = node
+                        .get("timestamp")
+                        .and_then(|ts| ts.parse().ok())
+                        .map(|ts| UNIX_EPOCH + Duration::from_millis(ts));
+                    match (active_since, last_seen) {
+                        (Some(active_since), Some(last_seen)) => last_seen >= active_since,
+                        _ => true,
+                    }
+                })
+                .collect()
+        })
+}
+
+/* `view` renders each stored node: `decode_hash` for `/v1/nodes`, `NodeView::from_hash` for `/v2/nodes` */
+fn active_nodes_json<V: Serialize>(
+    redis_actor: &Redis,
+    remove_inactive_after: Option<Duration>,
+    events: &Addr<Syn, EventHub>,
+    view: fn(HashMap<String, String>) -> V,
+) -> impl Stream<Item = bytes::Bytes, Error = actix_web::Error> {
+    let redis = redis_actor.clone();
+    let redis_rem = redis_actor.clone();
+    let events = events.clone();
+    let now = SystemTime::now();
+
+    let json = redis_actor
+        .as_redis_handle()
+        .scan_set("active_nodes".into(), 10)
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))
+        .map(move |chunk| {
+            let redis = redis.clone();
+            futures::stream::iter_ok(
+                chunk
+                    .into_iter()
+                    .map(move |node_id| {
+                        redis
+                            .as_redis_handle()
+                            .get_hash(format!("nodeinfo.{}", node_id))
+                            .and_then({
+                                let redis = redis.clone();
+                                move |node| add_reachability(&redis, node)
+                            })
+                            .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))
+                            .and_then(|mut node| {
+                                if let Some(ip_value) = node.get_mut("ip") {
+                                    *ip_value = obfuscate_ip(ip_value.as_str())
                                 }
-                            }))
+                                Ok((node_id, node))
+                            })
+                    }),
+            )
+        })
+        .flatten()
+        .map(move |node_fut| {
+            let redis_rem = redis_rem.clone();
+            let events = events.clone();
+            if let Some(timeout) = remove_inactive_after {
+                future::Either::B(node_fut.and_then(move |(node_id, node)| {
+                    if let Some(ts) = node.get("timestamp").and_then(|s| s.parse().ok()) {
+                        let ts = UNIX_EPOCH + Duration::from_millis(ts) + timeout;
+                        if ts < now {
+                            debug!("removing: ts={:?} now={:?} tmo={:?}", ts, now, timeout);
+                            let redis_touch = redis_rem.clone();
+                            future::Either::B(
+                                redis_rem
+                                    .as_redis_handle()
+                                    .remove_from_set("active_nodes".into(), node_id.clone())
+                                    .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))
+                                    .and_then(move |removed| {
+                                        if removed > 0 {
+                                            for cmd in ChangeMarker::touch_commands(millis_since_epoch(now)) {
+                                                redis_touch.do_send(cmd);
+                                            }
+                                            events.do_send(NodeEvent::new(EventKind::Expiry, node_id, None));
+                                        }
+                                        Ok(node)
+                                    }))
                         }
                         else {
-                            future::Either::A(node_fut.and_then(|(_, node)| Ok(node)))
+                            future::Either::A(future::ok(node))
                         }
-                    })
-                    .buffered(50)
+                    }
+                    else {
+                        future::Either::A(future::ok(node))
+                    }
+                }))
+            }
+            else {
+                future::Either::A(node_fut.and_then(|(_, node)| Ok(node)))
+            }
+        })
+        .buffered(50)
+        .map(view)
 /*                    .zip(futures::stream::iter_ok(0..))
-                    .and_then(move |(node, idx) : (HashMap<String, String>, u64)| {
-
-
-                        if idx == 0 {
-                            Ok(bytes::Bytes::from(format!(
-                                "[{}",
-                                serde_json::to_string(&node)?
-                            )))
-                        } else {
-                            Ok(bytes::Bytes::from(format!(
-                                ",\n{}",
-                                serde_json::to_string(&node)?
-                            )))
-                        }
-                    })
-                    .chain(futures::stream::once(Ok("]".into())))*/;
-                let json = ::stream_utils::stream_json_array(10240, 12288,
-                                                             |e| actix_web::error::ErrorInternalServerError(e.to_string()), json);
-
-                Ok::<_, actix_web::Error>(
-                    HttpResponse::Ok()
-                        .content_type("application/json")
-                        .header("cache-control", "public, max-age=30")
-                        .streaming(json),
-                )
-            })
+        .and_then(move |(node, idx) : (HashMap<String, String>, u64)| {
+
+
+            if idx == 0 {
+                Ok(bytes::Bytes::from(format!(
+                    "[{}",
+                    serde_json::to_string(&node)?
+                )))
+            } else {
+                Ok(bytes::Bytes::from(format!(
+                    ",\n{}",
+                    serde_json::to_string(&node)?
+                )))
+            }
+        })
+        .chain(futures::stream::once(Ok("]".into())))*/;
+    ::stream_utils::stream_json_array(
+        10240,
+        12288,
+        |e| actix_web::error::ErrorInternalServerError(e.to_string()),
+        json,
+    )
+}
+
+fn millis_since_epoch(t: SystemTime) -> u64 {
+    let since_epoch = t.duration_since(UNIX_EPOCH).unwrap();
+    since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_millis())
+}
+
+/* conditional responses and caching are skipped when the marker can't be read */
+fn change_marker(
+    redis: &Redis,
+    remove_inactive_after: Option<Duration>,
+) -> impl Future<Item = Option<ChangeMarker>, Error = actix_web::Error> {
+    redis
+        .as_redis_handle()
+        .get_change_marker()
+        .map(move |marker| {
+            marker.map(|marker| marker.expiring(remove_inactive_after, SystemTime::now()))
         })
+        .or_else(|e| {
+            warn!("reading change marker: {}", e);
+            Ok(None)
+        })
+}
+
+/* only bodies rendered under a change marker are kept, the others are streamed as they go */
+fn store_rendered(
+    cache: Rc<RefCell<RenderCache>>,
+    key: String,
+    marker: Option<ChangeMarker>,
+) -> Option<impl FnOnce(bytes::Bytes)> {
+    marker.map(|marker| move |body| cache.borrow_mut().put(key, &marker, body))
+}
+
+fn nodes_response(marker: Option<&ChangeMarker>) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::Ok();
+    builder
+        .content_type("application/json")
+        .header("cache-control", "public, max-age=30");
+    if let Some(marker) = marker {
+        with_validators(&mut builder, marker);
+    }
+    builder
+}
+
+fn dump_response(
+    format: DumpFormat,
+    encoding: ContentEncoding,
+    marker: Option<&ChangeMarker>,
+) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::Ok();
+    builder
+        .content_type(format.content_type())
+        .content_encoding(encoding)
+        .header("cache-control", "public, max-age=30")
+        .header(
+            "content-disposition",
+            format!(
+                "attachment; filename=\"golem-stats.{}\"",
+                format.extension()
+            ),
+        );
+    match marker {
+        Some(marker) => {
+            with_validators(&mut builder, marker);
+        }
+        None => {
+            builder.header(
+                http::header::LAST_MODIFIED,
+                LastModified(SystemTime::now().into()),
+            );
+        }
+    }
+    builder
+}
+
+/* upper bounds (in seconds) of the clock skew histogram buckets */
+static CLOCK_SKEW_BUCKETS: &[f64] = &[
+    -3600.0, -600.0, -60.0, -10.0, -1.0, 1.0, 10.0, 60.0, 600.0, 3600.0,
+];
+
+#[derive(Serialize, JsonSchema, Debug)]
+struct ClockSkewBucket {
+    /// exclusive upper bound, `null` for the last bucket
+    upper: Option<f64>,
+    count: u64,
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+struct ClockSkewDistribution {
+    nodes: u64,
+    skewed: u64,
+    min: Option<f64>,
+    max: Option<f64>,
+    buckets: Vec<ClockSkewBucket>,
+}
+
+impl ClockSkewDistribution {
+    fn new() -> Self {
+        ClockSkewDistribution {
+            nodes: 0,
+            skewed: 0,
+            min: None,
+            max: None,
+            buckets: CLOCK_SKEW_BUCKETS
+                .iter()
+                .map(|upper| Some(*upper))
+                .chain(Some(None))
+                .map(|upper| ClockSkewBucket { upper, count: 0 })
+                .collect(),
+        }
+    }
+
+    fn add(&mut self, skew: f64, skewed: bool) {
+        self.nodes += 1;
+        if skewed {
+            self.skewed += 1;
+        }
+        self.min = Some(self.min.map_or(skew, |min| min.min(skew)));
+        self.max = Some(self.max.map_or(skew, |max| max.max(skew)));
+
+        if let Some(bucket) = self
+            .buckets
+            .iter_mut()
+            .find(|bucket| bucket.upper.is_none_or(|upper| skew < upper))
+        {
+            bucket.count += 1;
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ReachabilityCheck {
+    success: bool,
+    timestamp: u64,
+}
+
+/* joins the latest ping-me result, looked up by cliid first and then by ip */
+fn add_reachability(
+    redis: &Redis,
+    mut node: HashMap<String, String>,
+) -> impl Future<Item = HashMap<String, String>, Error = actix_redis::RespError> {
+    let keys: Vec<String> = ["cliid", "ip"]
+        .iter()
+        .filter_map(|field| node.get(*field))
+        .map(|id| format!("pingme.{}", id))
+        .collect();
+
+    if keys.is_empty() {
+        return future::Either::A(future::ok(node));
+    }
+
+    future::Either::B(redis.as_redis_handle().get_values(keys).map(move |values| {
+        let check = values
+            .into_iter()
+            .flatten()
+            .filter_map(|value| serde_json::from_str::<ReachabilityCheck>(&value).ok())
+            .next();
+
+        if let Some(check) = check {
+            node.insert("reachable".into(), check.success.to_string());
+            node.insert(
+                "last_reachability_check".into(),
+                check.timestamp.to_string(),
+            );
+        }
+        node
+    }))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DumpFormat {
+    Csv,
+    Tsv,
+    Ndjson,
+}
+
+impl DumpFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            DumpFormat::Csv => "text/x-csv",
+            DumpFormat::Tsv => "text/tab-separated-values",
+            DumpFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            DumpFormat::Csv => "csv",
+            DumpFormat::Tsv => "tsv",
+            DumpFormat::Ndjson => "ndjson",
+        }
+    }
+
+    fn delimiter(&self) -> Option<u8> {
+        match self {
+            DumpFormat::Csv => Some(b','),
+            DumpFormat::Tsv => Some(b'\t'),
+            DumpFormat::Ndjson => None,
+        }
+    }
+
+    /* column names line, ndjson records are self describing */
+    fn header(&self, columns: Option<&[String]>) -> Option<bytes::Bytes> {
+        match (self.delimiter(), columns) {
+            (Some(delimiter), Some(columns)) => Some(bytes::Bytes::from(
+                columns.join(&(delimiter as char).to_string()) + "\n",
+            )),
+            _ => None,
+        }
     }
 }
 
-fn dump_csv_for_keys(
-    redis: &Addr<Unsync, RedisActor>,
+#[derive(Debug, PartialEq)]
+enum Columns {
+    /// configured `dump_columns`, legacy ones when not set
+    Default,
+    /// `CSV_FIELDS`, as expected by the old frontend
+    Legacy,
+    Listed(Vec<String>),
+    /// every field seen across the scanned nodes
+    All,
+}
+
+#[derive(Debug, PartialEq)]
+struct DumpParams {
+    format: DumpFormat,
+    encoding: ContentEncoding,
+    columns: Columns,
+}
+
+impl DumpParams {
+    /* e.g. `format=ndjson&compress=gzip` or `columns=node_id,net,version` */
+    fn from_query(query: &str) -> Result<Self, String> {
+        let mut params = DumpParams {
+            format: DumpFormat::Csv,
+            encoding: ContentEncoding::Auto,
+            columns: Columns::Default,
+        };
+
+        for (k, v) in parse(query.as_bytes()) {
+            match (k.as_ref(), v.as_ref()) {
+                ("format", "csv") => params.format = DumpFormat::Csv,
+                ("format", "tsv") => params.format = DumpFormat::Tsv,
+                ("format", "ndjson") => params.format = DumpFormat::Ndjson,
+                ("compress", "gzip") => params.encoding = ContentEncoding::Gzip,
+                ("compress", "none") => params.encoding = ContentEncoding::Identity,
+                ("profile", "default") => params.columns = Columns::Default,
+                ("profile", "legacy") => params.columns = Columns::Legacy,
+                ("columns", "*") => params.columns = Columns::All,
+                ("columns", columns) => {
+                    params.columns = Columns::Listed(
+                        columns
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|column| !column.is_empty())
+                            .map(String::from)
+                            .collect(),
+                    )
+                }
+                ("format", _) | ("compress", _) | ("profile", _) => {
+                    return Err(format!("unsupported {}: {}", k, v))
+                }
+                _ => debug!("unknown dump param {}={}", k, v),
+            }
+        }
+        Ok(params)
+    }
+}
+
+fn legacy_columns() -> Vec<String> {
+    CSV_FIELDS.iter().map(|field| field.to_string()).collect()
+}
+
+/* union of stored field names, plus the ones joined at dump time */
+fn all_fields(redis: &Redis) -> impl Future<Item = Vec<String>, Error = actix_web::Error> {
+    let redis_fields = redis.clone();
+
+    redis
+        .as_redis_handle()
+        .scan("nodeinfo.*".into(), 50)
+        .map(move |chunk| {
+            future::join_all(
+                chunk
+                    .into_iter()
+                    .map(|key| redis_fields.as_redis_handle().get_hash_fields(key))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .buffered(2)
+        .fold(BTreeSet::new(), |mut fields, chunk| {
+            fields.extend(chunk.into_iter().flatten());
+            Ok::<_, actix_redis::RespError>(fields)
+        })
+        .map(|mut fields| {
+            fields.insert("reachable".to_string());
+            fields.insert("last_reachability_check".to_string());
+            fields.into_iter().collect()
+        })
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))
+}
+
+fn dump_for_keys(
+    redis: &Redis,
     keys: Vec<String>,
+    format: DumpFormat,
+    columns: Option<Rc<Vec<String>>>,
 ) -> impl Future<Item = bytes::Bytes, Error = actix_web::Error> {
     future::join_all(
         keys.into_iter()
             .map(|key| {
+                let redis_join = redis.clone();
                 redis
                     .as_redis_handle()
                     .get_hash(key)
+                    .and_then(move |node| add_reachability(&redis_join, node))
                     .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))
             })
             .collect::<Vec<_>>(),
     )
-    .and_then(|nodes: Vec<HashMap<String, String>>| {
-        let buf = Vec::with_capacity(10240);
-
-        let mut csv_writer = csv::Writer::from_writer(buf);
-        for mut node in nodes {
-            csv_writer
-                .write_record(CSV_FIELDS.iter().map(|field_id| {
-                    match node.remove(map_csv_field(*field_id)) {
-                        Some(value) => match *field_id {
-                            "ip" => obfuscate_ip(value),
-                            _ => value,
-                        },
-                        None => String::default(),
-                    }
-                }))
-                .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
-        }
-        csv_writer.flush()?;
-        Ok(csv_writer
-            .into_inner()
-            .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
-            .into())
+    .and_then(move |nodes: Vec<HashMap<String, String>>| {
+        let columns = columns.as_ref().map(|c| c.as_slice());
+        let buf = match (format.delimiter(), columns) {
+            (Some(delimiter), Some(columns)) => write_delimited(nodes, delimiter, columns),
+            (Some(delimiter), None) => write_delimited(nodes, delimiter, &legacy_columns()),
+            (None, columns) => write_ndjson(nodes, columns),
+        };
+        buf.map(bytes::Bytes::from)
+            .map_err(actix_web::error::ErrorInternalServerError)
     })
 }
 
+fn column_value(node: &mut HashMap<String, String>, column: &str) -> String {
+    match node.remove(map_csv_field(column)) {
+        Some(value) => match map_csv_field(column) {
+            "ip" => obfuscate_ip(value),
+            _ => value,
+        },
+        None => String::default(),
+    }
+}
+
+fn write_delimited(
+    nodes: Vec<HashMap<String, String>>,
+    delimiter: u8,
+    columns: &[String],
+) -> Result<Vec<u8>, String> {
+    let buf = Vec::with_capacity(10240);
+
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(buf);
+    for mut node in nodes {
+        writer
+            .write_record(columns.iter().map(|column| column_value(&mut node, column)))
+            .map_err(|e| e.to_string())?
+    }
+    writer.flush().map_err(|e| e.to_string())?;
+    writer.into_inner().map_err(|e| e.to_string())
+}
+
+/* selected columns, serialized in the order they were asked for */
+struct Selected(Vec<(String, String)>);
+
+impl Serialize for Selected {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(column, value)| (column, value)))
+    }
+}
+
+/* one object per line carrying every stored field, in field name order, or only the
+ * selected columns */
+fn write_ndjson(
+    nodes: Vec<HashMap<String, String>>,
+    columns: Option<&[String]>,
+) -> Result<Vec<u8>, String> {
+    let mut buf = Vec::with_capacity(10240);
+
+    for mut node in nodes {
+        if let Some(columns) = columns {
+            let selected = Selected(
+                columns
+                    .iter()
+                    .map(|column| (column.clone(), column_value(&mut node, column)))
+                    .collect(),
+            );
+            serde_json::to_writer(&mut buf, &selected).map_err(|e| e.to_string())?;
+        } else {
+            if let Some(ip_value) = node.get_mut("ip") {
+                *ip_value = obfuscate_ip(ip_value.as_str())
+            }
+            serde_json::to_writer(&mut buf, &decode_hash(node)).map_err(|e| e.to_string())?;
+        }
+        buf.push(b'\n');
+    }
+    Ok(buf)
+}
+
 /* from output column name to redis field name  */
 fn map_csv_field(s: &str) -> &str {
     match s {
@@ -251,4 +854,193 @@ static CSV_FIELDS: &[&str] = &[
     "rs_finished_with_failures_total_time",
     "rs_failed_cnt",
     "rs_failed_total_time",
+    "reachable",
+    "last_reachability_check",
 ];
+
+fn query_parameter(name: &str, description: &str) -> serde_json::Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "description": description,
+        "schema": { "type": "string" }
+    })
+}
+
+/// Routes mounted by `route_list_nodes` in the OpenAPI document.
+pub fn describe(doc: &mut ApiDoc) {
+    let node_view = doc.add_schema("NodeView", node_view::node_schema());
+    let versions = doc.schema::<VersionReport>();
+    let clock_skew = doc.schema::<ClockSkewDistribution>();
+    let conditional =
+        json!({ "description": "unchanged since `If-None-Match` / `If-Modified-Since`" });
+
+    doc.add(
+        "/dump",
+        "get",
+        json!({
+            "summary": "every stored node, as csv, tsv or ndjson",
+            "parameters": [
+                query_parameter("format", "`csv` (default), `tsv` or `ndjson`"),
+                query_parameter("compress", "`gzip` or `none`"),
+                query_parameter("columns", "comma separated columns, `*` for every stored field"),
+                query_parameter("profile", "`default` or `legacy` column set")
+            ],
+            "responses": {
+                "200": openapi::response("one node per line", "text/csv", json!({ "type": "string" })),
+                "304": conditional,
+                "400": { "description": "unknown parameter value" }
+            }
+        }),
+    );
+    doc.add(
+        "/v1/nodes",
+        "get",
+        json!({
+            "summary": "active nodes, stored fields as strings",
+            "responses": {
+                "200": openapi::response("active nodes", "application/json", json!({
+                    "type": "array",
+                    "items": { "type": "object", "additionalProperties": true }
+                })),
+                "304": conditional
+            }
+        }),
+    );
+    doc.add(
+        "/v2/nodes",
+        "get",
+        json!({
+            "summary": "active nodes, stored fields converted to their types",
+            "responses": {
+                "200": openapi::response("active nodes", "application/json", json!({
+                    "type": "array",
+                    "items": node_view
+                })),
+                "304": conditional
+            }
+        }),
+    );
+    doc.add(
+        "/v2/schema",
+        "get",
+        json!({
+            "summary": "JSON Schema of the /v2/nodes response",
+            "responses": {
+                "200": openapi::response("JSON Schema", "application/schema+json", json!({ "type": "object" }))
+            }
+        }),
+    );
+    doc.add(
+        "/v1/versions",
+        "get",
+        json!({
+            "summary": "versions and protocol versions of active nodes",
+            "responses": { "200": openapi::response("version report", "application/json", versions) }
+        }),
+    );
+    doc.add(
+        "/v1/clock-skew",
+        "get",
+        json!({
+            "summary": "distribution of clock offsets of active nodes",
+            "responses": { "200": openapi::response("histogram", "application/json", clock_skew) }
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_skew_distribution() {
+        let mut distribution = ClockSkewDistribution::new();
+        distribution.add(-0.5, false);
+        distribution.add(0.2, false);
+        distribution.add(75.0, true);
+        distribution.add(-7200.0, true);
+        distribution.add(7200.0, true);
+
+        assert_eq!(distribution.nodes, 5);
+        assert_eq!(distribution.skewed, 3);
+        assert_eq!(distribution.min, Some(-7200.0));
+        assert_eq!(distribution.max, Some(7200.0));
+
+        let counts: Vec<u64> = distribution.buckets.iter().map(|b| b.count).collect();
+        assert_eq!(counts, vec![1, 0, 0, 0, 0, 2, 0, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn dump_params() {
+        assert_eq!(
+            DumpParams::from_query("").unwrap(),
+            DumpParams {
+                format: DumpFormat::Csv,
+                encoding: ContentEncoding::Auto,
+                columns: Columns::Default,
+            }
+        );
+        assert_eq!(
+            DumpParams::from_query("format=ndjson&compress=gzip").unwrap(),
+            DumpParams {
+                format: DumpFormat::Ndjson,
+                encoding: ContentEncoding::Gzip,
+                columns: Columns::Default,
+            }
+        );
+        assert_eq!(
+            DumpParams::from_query("columns=node_id,%20net,,ip")
+                .unwrap()
+                .columns,
+            Columns::Listed(vec!["node_id".into(), "net".into(), "ip".into()])
+        );
+        assert_eq!(
+            DumpParams::from_query("columns=*").unwrap().columns,
+            Columns::All
+        );
+        assert_eq!(
+            DumpParams::from_query("profile=legacy").unwrap().columns,
+            Columns::Legacy
+        );
+        assert!(DumpParams::from_query("format=xml").is_err());
+    }
+
+    #[test]
+    fn dump_formats() {
+        let node: HashMap<String, String> = vec![
+            ("cliid", "n1"),
+            ("ip", "10.1.2.3"),
+            ("protocol_version_p2p", "27"),
+            ("node_name", "a\tb"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        let ndjson = String::from_utf8(write_ndjson(vec![node.clone()], None).unwrap()).unwrap();
+        let parsed: HashMap<String, String> = serde_json::from_str(ndjson.trim_end()).unwrap();
+        assert_eq!(parsed["ip"], "10.x.x.x");
+        assert_eq!(parsed["protocol_version_p2p"], "27");
+        assert!(ndjson.ends_with("}\n"));
+
+        let selected = vec!["node_id".to_string(), "ip".to_string()];
+        let ndjson =
+            String::from_utf8(write_ndjson(vec![node.clone()], Some(&selected)).unwrap()).unwrap();
+        let parsed: HashMap<String, String> = serde_json::from_str(ndjson.trim_end()).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed["node_id"], "n1");
+        assert_eq!(parsed["ip"], "10.x.x.x");
+        assert_eq!(ndjson, "{\"node_id\":\"n1\",\"ip\":\"10.x.x.x\"}\n");
+
+        let tsv = String::from_utf8(
+            write_delimited(vec![node.clone()], b'\t', &legacy_columns()).unwrap(),
+        )
+        .unwrap();
+        assert!(tsv.starts_with("n1\t\"a\tb\"\t"));
+        assert_eq!(tsv.split('\t').count(), CSV_FIELDS.len() + 1);
+
+        let csv = String::from_utf8(write_delimited(vec![node], b',', &selected).unwrap()).unwrap();
+        assert_eq!(csv, "n1,10.x.x.x\n");
+    }
+}
@@ -6,22 +6,25 @@ extern crate tokio_core;
 
 extern crate bytes;
 extern crate config;
+extern crate flate2;
 extern crate url;
 
+extern crate schemars;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
-#[cfg_attr(test, macro_use)]
+#[macro_use]
 extern crate serde_json;
 
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 
+use actix::prelude::*;
 use actix_web::{http, server, App, HttpMessage, HttpRequest, HttpResponse};
 use config::{Config, ConfigError, Environment, File};
 use std::net::IpAddr;
-use std::time;
+use std::{cmp, env, path, process, time};
 
 #[cfg(feature = "redis")]
 extern crate actix_redis;
@@ -32,34 +35,99 @@ extern crate redis_async;
 #[cfg_attr(feature = "redis", macro_use)]
 mod redis_tools;
 
-#[cfg_attr(feature = "stats_update", macro_use)]
+#[cfg_attr(feature = "redis", macro_use)]
 extern crate failure;
 
+#[cfg(any(feature = "stats_update", feature = "list_nodes"))]
+mod counters;
+#[cfg(feature = "redis")]
+mod memory;
+#[cfg(feature = "redis")]
+mod replica;
+#[cfg(feature = "redis")]
+type Replicas = Vec<Addr<Syn, replica::Replica>>;
+#[cfg(not(feature = "redis"))]
+type Replicas = Vec<()>;
+#[cfg(feature = "redis")]
+mod spill;
+#[cfg(any(feature = "stats_update", feature = "pingme"))]
+type Spill = Option<spill::SpillLog>;
+#[cfg(not(any(feature = "stats_update", feature = "pingme")))]
+type Spill = Option<()>;
 #[cfg(feature = "stats_update")]
 mod stats_update;
-#[cfg(feature = "stats_update")]
+#[cfg(feature = "redis")]
 mod updater;
+#[cfg(feature = "stats_update")]
+mod webhook;
 
 #[cfg(feature = "list_nodes")]
 extern crate csv;
+#[cfg(feature = "list_nodes")]
+extern crate semver;
 
+#[cfg(feature = "list_nodes")]
+mod conditional;
 #[cfg(feature = "list_nodes")]
 mod list_nodes;
+#[cfg(feature = "list_nodes")]
+mod node_view;
+#[cfg(feature = "list_nodes")]
+mod versions;
 
+#[cfg(feature = "pingme")]
+extern crate libc;
 #[cfg(feature = "pingme")]
 extern crate nom;
+#[cfg(feature = "pingme")]
+extern crate tokio_io;
 
 #[cfg(feature = "pingme")]
 mod pingme;
 
+mod archive;
+mod events;
+mod health;
+mod openapi;
+mod shutdown;
 mod stream_utils;
 
 #[derive(Debug, Deserialize)]
-struct MonitorSettings {
+pub struct MonitorSettings {
     address: ::std::net::SocketAddr,
     redis: String,
+    redis_replicas: Option<String>,
     redirect: String,
     inactive: Option<u64>,
+    clock_skew_threshold: f64,
+    publish_channel: Option<String>,
+    publish_stream: Option<String>,
+    publish_stream_maxlen: Option<u64>,
+    #[cfg(feature = "pingme")]
+    pingme_timeout: u64,
+    #[cfg(feature = "pingme")]
+    pingme_max_ports: usize,
+    #[cfg(feature = "pingme")]
+    pingme_banner_send: String,
+    #[cfg(feature = "pingme")]
+    pingme_banner_expect: String,
+    #[cfg(feature = "pingme")]
+    pingme_result_ttl: u64,
+    dump_columns: Option<String>,
+    min_version: Option<String>,
+    min_protocol_version: Option<u64>,
+    archive_dir: Option<String>,
+    archive_rotate_mb: u64,
+    archive_rotate_secs: u64,
+    shutdown_timeout: u64,
+    updater_queue_capacity: usize,
+    updater_queue_policy: String,
+    spill_path: Option<String>,
+    memory_snapshot: Option<String>,
+    memory_snapshot_secs: u64,
+    #[cfg(feature = "stats_update")]
+    #[serde(default)]
+    webhooks: Vec<webhook::WebhookConfig>,
 }
 
 impl MonitorSettings {
@@ -73,10 +141,163 @@ impl MonitorSettings {
             .set_default("redis", "127.0.0.1:6379")?
             .set_default("redirect", "/show")?
             .set_default("inactive", Some(120))?
+            .set_default("clock_skew_threshold", 60.0)?
+            .set_default("pingme_timeout", 5)?
+            .set_default("pingme_max_ports", 5)?
+            .set_default("pingme_banner_send", "")?
+            .set_default("pingme_banner_expect", "")?
+            .set_default("pingme_result_ttl", 86400)?
+            .set_default("archive_rotate_mb", 64)?
+            .set_default("archive_rotate_secs", 3600)?
+            .set_default("shutdown_timeout", 10)?
+            .set_default("updater_queue_capacity", 10000)?
+            .set_default("updater_queue_policy", "reject")?
+            .set_default("memory_snapshot_secs", 60)?
             .merge(File::with_name("golem-monitor").required(false))?
             .merge(env)?;
 
-        config.try_into()
+        let settings: MonitorSettings = config.try_into()?;
+        #[cfg(feature = "redis")]
+        settings.updater_queue_policy().map_err(|e| {
+            ConfigError::Message(format!("GOLEM_MONITOR_UPDATER_QUEUE_POLICY: {}", e))
+        })?;
+        Ok(settings)
+    }
+
+    #[cfg(feature = "redis")]
+    fn updater_queue_policy(&self) -> Result<updater::QueuePolicy, String> {
+        self.updater_queue_policy.parse()
+    }
+
+    #[cfg(any(feature = "stats_update", feature = "pingme"))]
+    fn publish_targets(&self) -> Vec<updater::Publish> {
+        let channel = self
+            .publish_channel
+            .iter()
+            .map(|channel| updater::Publish::Channel(channel.clone()));
+        let stream = self
+            .publish_stream
+            .iter()
+            .map(|key| updater::Publish::Stream {
+                key: key.clone(),
+                maxlen: self.publish_stream_maxlen,
+            });
+
+        channel.chain(stream).collect()
+    }
+
+    #[cfg(any(feature = "stats_update", feature = "pingme"))]
+    fn updater_config(&self, spill: Spill, replicas: Replicas) -> updater::UpdaterConfig {
+        updater::UpdaterConfig {
+            publish: self.publish_targets(),
+            queue_capacity: self.updater_queue_capacity,
+            // checked by `load`
+            queue_policy: self
+                .updater_queue_policy()
+                .unwrap_or(updater::QueuePolicy::Reject),
+            spill,
+            replicas,
+        }
+    }
+
+    /* comma separated `host:port` of redis instances writes are copied to */
+    #[cfg(feature = "redis")]
+    fn redis_replicas(&self) -> Vec<String> {
+        self.redis_replicas
+            .iter()
+            .flat_map(|replicas| replicas.split(','))
+            .map(str::trim)
+            .filter(|replica| !replica.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    /* one per replica, shared by the updaters of every worker */
+    #[cfg(feature = "redis")]
+    fn start_replicas(&self) -> Replicas {
+        self.redis_replicas()
+            .into_iter()
+            .map(|address| replica::Replica::start(address, self.updater_queue_capacity))
+            .collect()
+    }
+
+    #[cfg(not(feature = "redis"))]
+    fn start_replicas(&self) -> Replicas {
+        Vec::new()
+    }
+
+    /* comma separated, e.g. `node_id,version,net` */
+    #[cfg(feature = "list_nodes")]
+    fn dump_columns(&self) -> Option<Vec<String>> {
+        self.dump_columns.as_ref().map(|columns| {
+            columns
+                .split(',')
+                .map(str::trim)
+                .filter(|column| !column.is_empty())
+                .map(String::from)
+                .collect()
+        })
+    }
+
+    #[cfg(any(feature = "stats_update", feature = "pingme"))]
+    fn start_spill(&self) -> Spill {
+        let path = path::PathBuf::from(self.spill_path.as_ref()?);
+
+        info!("spilling failed redis writes to {}", path.display());
+        Some(spill::SpillLog::start(path))
+    }
+
+    #[cfg(not(any(feature = "stats_update", feature = "pingme")))]
+    fn start_spill(&self) -> Spill {
+        None
+    }
+
+    /* loads and periodically saves the in-memory store when `redis` is `memory` */
+    #[cfg(feature = "redis")]
+    fn start_memory(&self) {
+        if self.redis != memory::MEMORY_ADDRESS {
+            return;
+        }
+        info!("keeping node data in memory, redis is not used");
+        if let Some(ref path) = self.memory_snapshot {
+            info!("saving the memory store to {}", path);
+            Arbiter::system_registry()
+                .get::<memory::MemoryStore>()
+                .do_send(memory::Persist {
+                    path: path::PathBuf::from(path),
+                    interval: time::Duration::from_secs(self.memory_snapshot_secs),
+                });
+        }
+    }
+
+    #[cfg(not(feature = "redis"))]
+    fn start_memory(&self) {}
+
+    #[cfg(feature = "stats_update")]
+    fn start_counters(&self) -> Addr<Syn, counters::Tracker> {
+        counters::Tracker::new(redis_tools::Redis::connect(&self.redis)).start()
+    }
+
+    #[cfg(not(feature = "stats_update"))]
+    fn start_counters(&self) {}
+
+    #[cfg(feature = "stats_update")]
+    fn start_webhooks(&self) -> webhook::Webhooks {
+        webhook::Webhooks::start(&self.webhooks)
+    }
+
+    #[cfg(not(feature = "stats_update"))]
+    fn start_webhooks(&self) {}
+
+    fn start_archive(&self) -> Option<Addr<Syn, archive::Archiver>> {
+        let dir = path::PathBuf::from(self.archive_dir.as_ref()?);
+        let rotate_bytes = self.archive_rotate_mb * 1024 * 1024;
+        let rotate_after = time::Duration::from_secs(self.archive_rotate_secs);
+
+        info!("archiving requests in {}", dir.display());
+        Some(SyncArbiter::start(1, move || {
+            archive::Archiver::new(dir.clone(), rotate_bytes, rotate_after)
+        }))
     }
 }
 
@@ -89,63 +310,224 @@ fn main() {
     }
     env_logger::init();
 
+    let settings = match MonitorSettings::load() {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("invalid configuration: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("replay") {
+        return replay(&settings, args[1..].to_vec());
+    }
+
     let sys = actix::System::new("golem-monitor");
 
-    let settings = MonitorSettings::load().unwrap();
     let address = settings.address.clone();
+    let shutdown_timeout = settings.shutdown_timeout;
+    // the server counts its shutdown timeout in u16 seconds
+    let server_shutdown_timeout = cmp::min(shutdown_timeout, u64::from(u16::MAX)) as u16;
+    if u64::from(server_shutdown_timeout) < shutdown_timeout {
+        warn!(
+            "SHUTDOWN_TIMEOUT {} too long, waiting at most {} s for requests in progress",
+            shutdown_timeout, server_shutdown_timeout
+        );
+    }
+    let events: Addr<Syn, _> = events::EventHub::default().start();
+    let archive = settings.start_archive();
+    let spill = settings.start_spill();
+    let replicas = settings.start_replicas();
+    let webhooks = settings.start_webhooks();
+    settings.start_memory();
+    let counters = settings.start_counters();
 
     info!("Starting server on {}", &address);
 
-    server::new(move || {
+    let server = server::new(move || {
+        let updaters = health::WorkerUpdaters::default();
+
         App::new()
             .middleware(actix_web::middleware::Logger::default())
-            .configure(route_pingme)
-            .configure(route_list_nodes(
-                settings.redis.clone(),
-                settings.inactive.map(time::Duration::from_secs),
+            .configure(route_pingme(
+                &settings,
+                spill.clone(),
+                replicas.clone(),
+                updaters.clone(),
             ))
+            .configure(route_list_nodes(&settings, events.clone()))
             .configure(route_stats_update(
-                settings.redis.clone(),
-                settings.redirect.clone(),
+                &settings,
+                events.clone(),
+                counters.clone(),
+                archive.clone(),
+                spill.clone(),
+                replicas.clone(),
+                webhooks.clone(),
+                updaters.clone(),
             ))
+            .configure(events::route_events(events.clone()))
+            .configure(openapi::route_openapi())
+            .configure(health::route_health(&settings, updaters))
     })
     .bind(address)
     .unwrap()
+    .disable_signals()
+    .shutdown_timeout(server_shutdown_timeout)
     .start();
 
+    Arbiter::system_registry()
+        .get::<shutdown::Shutdown>()
+        .do_send(shutdown::Configure {
+            server: server.recipient(),
+            deadline: time::Duration::from_secs(shutdown_timeout),
+        });
+
     let _ = sys.run();
 }
 
+/* `golem-monitor-rust replay <archive.ndjson.gz>...` re-ingests archived requests
+ * into the configured redis, which is expected to be empty */
+#[cfg(feature = "stats_update")]
+fn replay(settings: &MonitorSettings, paths: Vec<String>) {
+    use futures::Future;
+    use redis_tools::Redis;
+
+    if paths.is_empty() {
+        return error!("replay: no archive files given");
+    }
+
+    let sys = actix::System::new("golem-monitor-replay");
+
+    let redis_actor = Redis::connect(&settings.redis);
+    // replayed reports are not announced again
+    let updater = updater::Updater::start(redis_actor.clone(), updater::UpdaterConfig::default());
+    let events: Addr<Syn, _> = events::EventHub::default().start();
+    let counters = settings.start_counters();
+    let records = archive::read_archives(paths);
+
+    Arbiter::handle().spawn(
+        stats_update::replay(
+            updater,
+            counters,
+            events,
+            settings.clock_skew_threshold,
+            records,
+        )
+        .then(|r| {
+            match r {
+                Ok(n) => info!("replay: {} records stored", n),
+                Err(e) => error!("replay failed: {}", e),
+            }
+            Arbiter::system().do_send(actix::msgs::SystemExit(0));
+            Ok(())
+        }),
+    );
+
+    let _ = sys.run();
+}
+
+#[cfg(not(feature = "stats_update"))]
+fn replay(_: &MonitorSettings, _: Vec<String>) {
+    error!("replay requires the stats_update feature");
+}
+
 #[cfg(feature = "list_nodes")]
 pub use list_nodes::route_list_nodes;
 
 #[cfg(not(feature = "list_nodes"))]
-fn route_list_nodes(_: String, _: Option<time::Duration>) -> impl Fn(App) -> App {
+fn route_list_nodes(_: &MonitorSettings, _: Addr<Syn, events::EventHub>) -> impl Fn(App) -> App {
     |app| app
 }
 
 #[cfg(feature = "pingme")]
-fn route_pingme(app: App) -> App {
+fn route_pingme(
+    settings: &MonitorSettings,
+    spill: Spill,
+    replicas: Replicas,
+    updaters: health::WorkerUpdaters,
+) -> impl Fn(App) -> App {
     info!("mounting ping-me");
-    app.route("/ping-me", http::Method::POST, pingme::ping_me)
+    let config = pingme::PingMeConfig {
+        timeout: time::Duration::from_secs(settings.pingme_timeout),
+        max_ports: settings.pingme_max_ports,
+        banner_send: settings.pingme_banner_send.clone().into_bytes(),
+        banner_expect: settings.pingme_banner_expect.clone().into_bytes(),
+        result_ttl: time::Duration::from_secs(settings.pingme_result_ttl),
+    };
+    let redis_address = settings.redis.clone();
+    let updater_config = settings.updater_config(spill, replicas);
+
+    move |app: App| -> App {
+        use redis_tools::Redis;
+
+        let config = config.clone();
+        let redis = Redis::connect(&redis_address);
+        let updater = updater::Updater::start(redis.clone(), updater_config.clone());
+        updaters.add(updater.clone());
+
+        app.resource("/ping-me", move |r| {
+            r.method(http::Method::POST)
+                .f(move |req| pingme::ping_me(req, &config, &updater, &redis))
+        })
+    }
 }
 
 #[cfg(not(feature = "pingme"))]
-fn route_pingme(app: App) -> App {
-    app
+fn route_pingme(
+    _: &MonitorSettings,
+    _: Spill,
+    _: Replicas,
+    _: health::WorkerUpdaters,
+) -> impl Fn(App) -> App {
+    |app| app
 }
 
 #[cfg(feature = "stats_update")]
-fn route_stats_update(redis_address: String, redirect_to: String) -> impl Fn(App) -> App {
+fn route_stats_update(
+    settings: &MonitorSettings,
+    events: Addr<Syn, events::EventHub>,
+    counters: Addr<Syn, counters::Tracker>,
+    archive: Option<Addr<Syn, archive::Archiver>>,
+    spill: Spill,
+    replicas: Replicas,
+    webhooks: webhook::Webhooks,
+    updaters: health::WorkerUpdaters,
+) -> impl Fn(App) -> App {
     info!("mounting stats update");
-    use actix_redis::RedisActor;
+    use redis_tools::Redis;
+
+    let redis_address = settings.redis.clone();
+    let redirect_to = settings.redirect.clone();
+    let clock_skew_threshold = settings.clock_skew_threshold;
+    let updater_config = settings.updater_config(spill, replicas);
 
     move |app: App| -> App {
         let redirect_to = redirect_to.clone();
-        let redis_actor = RedisActor::start(redis_address.clone());
-
-        let update_handler_root = stats_update::UpdateHandler::new(redis_actor.clone());
-        let update_handler_update = stats_update::UpdateHandler::new(redis_actor);
+        let redis_actor = Redis::connect(&redis_address);
+
+        let update_handler_root = stats_update::UpdateHandler::new(
+            redis_actor.clone(),
+            updater_config.clone(),
+            counters.clone(),
+            events.clone(),
+            archive.clone(),
+            webhooks.clone(),
+            clock_skew_threshold,
+        );
+        let update_handler_update = stats_update::UpdateHandler::new(
+            redis_actor.clone(),
+            updater_config.clone(),
+            counters.clone(),
+            events.clone(),
+            archive.clone(),
+            webhooks.clone(),
+            clock_skew_threshold,
+        );
+
+        updaters.add(update_handler_root.updater());
+        updaters.add(update_handler_update.updater());
 
         app.resource("/", move |r| {
             r.method(http::Method::GET).h(move |_r| {
@@ -162,7 +544,16 @@ fn route_stats_update(redis_address: String, redirect_to: String) -> impl Fn(App
 }
 
 #[cfg(not(feature = "stats_update"))]
-fn route_stats_update(_: String) -> impl Fn(App) -> App {
+fn route_stats_update(
+    _: &MonitorSettings,
+    _: Addr<Syn, events::EventHub>,
+    _: (),
+    _: Option<Addr<Syn, archive::Archiver>>,
+    _: Spill,
+    _: Replicas,
+    _: (),
+    _: health::WorkerUpdaters,
+) -> impl Fn(App) -> App {
     |app| app
 }
 
@@ -0,0 +1,588 @@
+use actix::prelude::*;
+use actix_redis::{Command, Error, RespValue};
+use serde_json;
+use shutdown::{RegisterStore, Shutdown};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// `redis` address selecting the in-process store instead of a redis server.
+pub static MEMORY_ADDRESS: &str = "memory";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Entry {
+    Hash(BTreeMap<String, String>),
+    Set(BTreeSet<String>),
+    #[serde(rename = "string")]
+    Text(String),
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    data: &'a BTreeMap<String, Entry>,
+    expires: &'a BTreeMap<String, u64>,
+}
+
+#[derive(Deserialize)]
+struct SnapshotData {
+    data: BTreeMap<String, Entry>,
+    #[serde(default)]
+    expires: BTreeMap<String, u64>,
+}
+
+/// Keeps `nodeinfo.*`, `p2pstats.*`, `active_nodes` and the change marker in process,
+/// answering the subset of redis commands the monitor sends. One instance is shared
+/// by every worker, optionally snapshotted to a JSON file and loaded back on start.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: BTreeMap<String, Entry>,
+    /// millis since epoch keys set with `EX` are removed at
+    expires: BTreeMap<String, u64>,
+    /// earliest of `expires`
+    next_expiry: Option<u64>,
+    snapshot: Option<PathBuf>,
+    /// writes since the last snapshot
+    dirty: bool,
+}
+
+impl Actor for MemoryStore {
+    type Context = Context<Self>;
+}
+
+impl Supervised for MemoryStore {}
+
+impl SystemService for MemoryStore {}
+
+fn ok() -> RespValue {
+    RespValue::SimpleString("OK".into())
+}
+
+fn bulk(value: &str) -> RespValue {
+    RespValue::BulkString(value.as_bytes().to_vec())
+}
+
+fn wrong_type() -> RespValue {
+    RespValue::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())
+}
+
+fn to_arg(value: RespValue) -> String {
+    match value {
+        RespValue::BulkString(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        RespValue::SimpleString(s) => s,
+        RespValue::Integer(i) => i.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn now_millis() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() * 1000 + now.subsec_millis() as u64
+}
+
+fn is_write(name: &str) -> bool {
+    ["SET", "INCR", "HMSET", "HINCRBY", "HDEL", "SADD", "SREM"]
+        .iter()
+        .any(|write| name.eq_ignore_ascii_case(write))
+}
+
+/* `SCAN` patterns the monitor uses: literal text with `*` wildcards */
+fn matches(pattern: &str, key: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !key.starts_with(first) {
+        return false;
+    }
+    let mut rest = &key[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        if i + 1 == parts.len() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+impl MemoryStore {
+    fn hash(&mut self, key: &str) -> Result<&mut BTreeMap<String, String>, RespValue> {
+        match self
+            .data
+            .entry(key.to_string())
+            .or_insert_with(|| Entry::Hash(BTreeMap::new()))
+        {
+            Entry::Hash(hash) => Ok(hash),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    fn set(&mut self, key: &str) -> Result<&mut BTreeSet<String>, RespValue> {
+        match self
+            .data
+            .entry(key.to_string())
+            .or_insert_with(|| Entry::Set(BTreeSet::new()))
+        {
+            Entry::Set(set) => Ok(set),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    fn expire(&mut self, now: u64) {
+        match self.next_expiry {
+            Some(at) if at <= now => (),
+            _ => return,
+        }
+        let expired: Vec<String> = self
+            .expires
+            .iter()
+            .filter(|&(_, &at)| at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.expires.remove(&key);
+            self.data.remove(&key);
+        }
+        self.next_expiry = self.expires.values().cloned().min();
+        self.dirty = true;
+    }
+
+    fn incr(&mut self, key: &str, by: i64, field: Option<&str>) -> RespValue {
+        let value = match field {
+            Some(field) => match self.hash(key) {
+                Ok(hash) => hash.entry(field.to_string()).or_insert_with(|| "0".into()),
+                Err(e) => return e,
+            },
+            None => match self
+                .data
+                .entry(key.to_string())
+                .or_insert_with(|| Entry::Text("0".into()))
+            {
+                Entry::Text(value) => value,
+                _ => return wrong_type(),
+            },
+        };
+        match value.parse::<i64>().ok().and_then(|n| n.checked_add(by)) {
+            Some(n) => {
+                *value = n.to_string();
+                RespValue::Integer(n)
+            }
+            None => RespValue::Error("ERR value is not an integer or out of range".into()),
+        }
+    }
+
+    fn execute(&mut self, command: Vec<String>, now: u64) -> RespValue {
+        let name = match command.first() {
+            Some(name) => name.to_uppercase(),
+            None => return RespValue::Error("ERR empty command".into()),
+        };
+        let args = &command[1..];
+
+        match (name.as_str(), args) {
+            ("PING", []) => RespValue::SimpleString("PONG".into()),
+            ("CLIENT", _) => ok(),
+            // nobody can subscribe in process
+            ("PUBLISH", [_, _]) => RespValue::Integer(0),
+            ("XADD", [_, ..]) => bulk(&format!("{}-0", now)),
+            ("SET", [key, value]) => {
+                self.data.insert(key.clone(), Entry::Text(value.clone()));
+                self.expires.remove(key);
+                ok()
+            }
+            ("SET", [key, value, ex, secs]) if ex.eq_ignore_ascii_case("EX") => {
+                let at = match secs.parse::<u64>() {
+                    Ok(secs) if secs > 0 => now + secs * 1000,
+                    _ => return RespValue::Error("ERR invalid expire time in set".into()),
+                };
+                self.data.insert(key.clone(), Entry::Text(value.clone()));
+                self.expires.insert(key.clone(), at);
+                self.next_expiry = Some(self.next_expiry.map_or(at, |next| next.min(at)));
+                ok()
+            }
+            ("MGET", keys) if !keys.is_empty() => RespValue::Array(
+                keys.iter()
+                    .map(|key| match self.data.get(key) {
+                        Some(Entry::Text(value)) => bulk(value),
+                        _ => RespValue::Nil,
+                    })
+                    .collect(),
+            ),
+            ("INCR", [key]) => self.incr(key, 1, None),
+            ("HINCRBY", [key, field, by]) => match by.parse() {
+                Ok(by) => self.incr(key, by, Some(field)),
+                Err(_) => RespValue::Error("ERR value is not an integer or out of range".into()),
+            },
+            ("HMSET", [key, fields @ ..]) if !fields.is_empty() && fields.len() % 2 == 0 => {
+                match self.hash(key) {
+                    Ok(hash) => {
+                        for pair in fields.chunks(2) {
+                            hash.insert(pair[0].clone(), pair[1].clone());
+                        }
+                        ok()
+                    }
+                    Err(e) => e,
+                }
+            }
+            ("HDEL", [key, fields @ ..]) if !fields.is_empty() => {
+                let (removed, empty) = match self.data.get_mut(key) {
+                    Some(Entry::Hash(hash)) => (
+                        fields
+                            .iter()
+                            .filter(|field| hash.remove(*field).is_some())
+                            .count(),
+                        hash.is_empty(),
+                    ),
+                    Some(_) => return wrong_type(),
+                    None => (0, false),
+                };
+                if empty {
+                    self.data.remove(key);
+                }
+                RespValue::Integer(removed as i64)
+            }
+            ("HGETALL", [key]) => match self.data.get(key) {
+                Some(Entry::Hash(hash)) => RespValue::Array(
+                    hash.iter()
+                        .flat_map(|(field, value)| vec![bulk(field), bulk(value)])
+                        .collect(),
+                ),
+                Some(_) => wrong_type(),
+                None => RespValue::Array(Vec::new()),
+            },
+            ("HKEYS", [key]) => match self.data.get(key) {
+                Some(Entry::Hash(hash)) => {
+                    RespValue::Array(hash.keys().map(|field| bulk(field)).collect())
+                }
+                Some(_) => wrong_type(),
+                None => RespValue::Array(Vec::new()),
+            },
+            ("SADD", [key, members @ ..]) if !members.is_empty() => match self.set(key) {
+                Ok(set) => RespValue::Integer(
+                    members
+                        .iter()
+                        .filter(|member| set.insert((*member).clone()))
+                        .count() as i64,
+                ),
+                Err(e) => e,
+            },
+            ("SREM", [key, members @ ..]) if !members.is_empty() => {
+                let (removed, empty) = match self.data.get_mut(key) {
+                    Some(Entry::Set(set)) => (
+                        members.iter().filter(|member| set.remove(*member)).count(),
+                        set.is_empty(),
+                    ),
+                    Some(_) => return wrong_type(),
+                    None => (0, false),
+                };
+                if empty {
+                    self.data.remove(key);
+                }
+                RespValue::Integer(removed as i64)
+            }
+            // the whole set in a single page, `COUNT` is only a hint
+            ("SSCAN", [key, _, ..]) => match self.data.get(key) {
+                Some(Entry::Set(set)) => RespValue::Array(vec![
+                    bulk("0"),
+                    RespValue::Array(set.iter().map(|member| bulk(member)).collect()),
+                ]),
+                Some(_) => wrong_type(),
+                None => RespValue::Array(vec![bulk("0"), RespValue::Array(Vec::new())]),
+            },
+            ("SCAN", [_, options @ ..]) => {
+                let pattern = options
+                    .iter()
+                    .position(|option| option.eq_ignore_ascii_case("MATCH"))
+                    .and_then(|i| options.get(i + 1))
+                    .map(String::as_str)
+                    .unwrap_or("*");
+                RespValue::Array(vec![
+                    bulk("0"),
+                    RespValue::Array(
+                        self.data
+                            .keys()
+                            .filter(|key| matches(pattern, key))
+                            .map(|key| bulk(key))
+                            .collect(),
+                    ),
+                ])
+            }
+            _ => RespValue::Error(format!("ERR unsupported command '{}' in memory mode", name)),
+        }
+    }
+
+    fn load(&mut self, path: &Path) -> io::Result<()> {
+        let snapshot: SnapshotData = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        self.data = snapshot.data;
+        self.expires = snapshot.expires;
+        self.next_expiry = self.expires.values().cloned().min();
+        Ok(())
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        let path = match self.snapshot {
+            Some(ref path) if self.dirty => path.clone(),
+            _ => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("saving");
+        {
+            let mut file = BufWriter::new(File::create(&tmp)?);
+            serde_json::to_writer(
+                &mut file,
+                &SnapshotRef {
+                    data: &self.data,
+                    expires: &self.expires,
+                },
+            )?;
+            file.flush()?;
+            file.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp, &path)?;
+
+        self.dirty = false;
+        debug!("{} keys saved to {}", self.data.len(), path.display());
+        Ok(())
+    }
+}
+
+impl MemoryStore {
+    fn save_later(&mut self, interval: Duration, ctx: &mut Context<Self>) {
+        ctx.run_later(interval, move |act, ctx| {
+            if let Err(e) = act.save() {
+                error!("memory snapshot failed: {}", e)
+            }
+            act.save_later(interval, ctx);
+        });
+    }
+}
+
+impl Handler<Command> for MemoryStore {
+    type Result = Result<RespValue, Error>;
+
+    fn handle(&mut self, msg: Command, _: &mut Self::Context) -> Self::Result {
+        let command = match msg.0 {
+            RespValue::Array(args) => args.into_iter().map(to_arg).collect(),
+            arg => vec![to_arg(arg)],
+        };
+        let now = now_millis();
+        self.expire(now);
+        if command.first().is_some_and(|name| is_write(name)) {
+            self.dirty = true;
+        }
+        Ok(self.execute(command, now))
+    }
+}
+
+/// Loads `path`, if it exists, and saves the store there every `interval` and on shutdown.
+pub struct Persist {
+    pub path: PathBuf,
+    pub interval: Duration,
+}
+
+impl Message for Persist {
+    type Result = ();
+}
+
+impl Handler<Persist> for MemoryStore {
+    type Result = ();
+
+    fn handle(&mut self, msg: Persist, ctx: &mut Self::Context) {
+        match self.load(&msg.path) {
+            Ok(()) => info!(
+                "{} keys loaded from {}",
+                self.data.len(),
+                msg.path.display()
+            ),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => error!("unable to load {}: {}", msg.path.display(), e),
+        }
+        self.snapshot = Some(msg.path);
+
+        self.save_later(msg.interval, ctx);
+        Arbiter::system_registry()
+            .get::<Shutdown>()
+            .do_send(RegisterStore(ctx.address()));
+    }
+}
+
+/// Saves the store right away, if it changed since the last snapshot.
+pub struct Snapshot;
+
+impl Message for Snapshot {
+    type Result = Result<(), io::Error>;
+}
+
+impl Handler<Snapshot> for MemoryStore {
+    type Result = Result<(), io::Error>;
+
+    fn handle(&mut self, _: Snapshot, _: &mut Self::Context) -> Self::Result {
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future, Future, Stream};
+    use redis_tools::{AsRedisHandle, Redis};
+    use std::collections::HashMap;
+    use std::env;
+    use updater::{UpdateMap, UpdateRedis, Updater, UpdaterConfig};
+
+    fn run(store: &mut MemoryStore, command: &[&str]) -> RespValue {
+        store.execute(command.iter().map(|arg| arg.to_string()).collect(), 1000)
+    }
+
+    #[test]
+    fn commands() {
+        let mut store = MemoryStore::default();
+        run(
+            &mut store,
+            &["HMSET", "nodeinfo.n1", "version", "0.15", "os", "linux"],
+        );
+        run(&mut store, &["SET", "p2pstats.n1", "{}"]);
+        assert_eq!(
+            run(&mut store, &["SADD", "active_nodes", "n1", "n2"]),
+            RespValue::Integer(2)
+        );
+        assert_eq!(
+            run(&mut store, &["INCR", "nodes.generation"]),
+            RespValue::Integer(1)
+        );
+
+        assert_eq!(
+            run(&mut store, &["HGETALL", "nodeinfo.n1"]),
+            RespValue::Array(vec![
+                bulk("os"),
+                bulk("linux"),
+                bulk("version"),
+                bulk("0.15")
+            ])
+        );
+        assert_eq!(
+            run(&mut store, &["MGET", "p2pstats.n1", "p2pstats.n2"]),
+            RespValue::Array(vec![bulk("{}"), RespValue::Nil])
+        );
+        assert_eq!(
+            run(
+                &mut store,
+                &["SCAN", "0", "MATCH", "nodeinfo.*", "COUNT", "10"]
+            ),
+            RespValue::Array(vec![bulk("0"), RespValue::Array(vec![bulk("nodeinfo.n1")])])
+        );
+        assert_eq!(
+            run(&mut store, &["SREM", "active_nodes", "n2"]),
+            RespValue::Integer(1)
+        );
+        assert_eq!(
+            run(&mut store, &["SSCAN", "active_nodes", "0", "COUNT", "10"]),
+            RespValue::Array(vec![bulk("0"), RespValue::Array(vec![bulk("n1")])])
+        );
+        assert_eq!(run(&mut store, &["INCR", "nodeinfo.n1"]), wrong_type());
+        assert_eq!(
+            run(&mut store, &["HDEL", "nodeinfo.n1", "os", "os:json"]),
+            RespValue::Integer(1)
+        );
+    }
+
+    #[test]
+    fn expiry() {
+        let mut store = MemoryStore::default();
+        run(&mut store, &["SET", "pingme.n1", "{}", "EX", "60"]);
+        run(&mut store, &["SET", "pingme.n2", "{}"]);
+
+        store.expire(60_999);
+        assert!(store.data.contains_key("pingme.n1"));
+        store.expire(61_000);
+        assert!(!store.data.contains_key("pingme.n1"));
+        assert!(store.data.contains_key("pingme.n2"));
+        assert_eq!(store.next_expiry, None);
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let path = env::temp_dir()
+            .join(format!("golem-monitor-memory-{}", ::std::process::id()))
+            .join("store.json");
+        let mut store = MemoryStore {
+            snapshot: Some(path.clone()),
+            ..MemoryStore::default()
+        };
+        run(&mut store, &["HMSET", "nodeinfo.n1", "version", "0.15"]);
+        run(&mut store, &["SADD", "active_nodes", "n1"]);
+        run(&mut store, &["SET", "pingme.n1", "{}", "EX", "60"]);
+        store.dirty = true;
+        store.save().unwrap();
+
+        let mut loaded = MemoryStore::default();
+        loaded.load(&path).unwrap();
+        assert_eq!(loaded.data, store.data);
+        assert_eq!(loaded.expires, store.expires);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn through_redis_handle() {
+        let mut sys = System::new("memory-test");
+        let redis = Redis::Memory(MemoryStore::default().start());
+        let updater = Updater::start(redis.clone(), UpdaterConfig::default());
+        let node = |fields: &[(&str, &str)]| {
+            UpdateRedis::UpdateRedisMap(UpdateMap {
+                collection: "nodeinfo".into(),
+                key: "n1".into(),
+                value: fields
+                    .iter()
+                    .map(|&(field, value)| (field.to_string(), value.to_string()))
+                    .collect(),
+                document: None,
+            })
+        };
+
+        let read = sys.run_until_complete(future::lazy(|| {
+            let reader = redis.clone();
+            updater
+                .send(node(&[("version", "0.15"), ("os:json", "\"linux\"")]))
+                .and_then(move |_| updater.send(node(&[("os", "linux")])))
+                .map_err(|e| e.to_string())
+                .and_then(move |_| {
+                    reader
+                        .send(Command(resp_array!["SADD", "active_nodes", "n1"]))
+                        .map_err(|e| e.to_string())
+                        .and_then(move |_| {
+                            reader
+                                .as_redis_handle()
+                                .scan_set("active_nodes".into(), 10)
+                                .concat2()
+                                .and_then(move |nodes| {
+                                    future::join_all(
+                                        nodes
+                                            .into_iter()
+                                            .map(|node| {
+                                                reader
+                                                    .as_redis_handle()
+                                                    .get_hash(format!("nodeinfo.{}", node))
+                                            })
+                                            .collect::<Vec<_>>(),
+                                    )
+                                })
+                                .map_err(|e| e.to_string())
+                        })
+                })
+        }));
+
+        let expected: HashMap<String, String> = vec![("version", "0.15"), ("os", "linux")]
+            .into_iter()
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(read, Ok(vec![expected]));
+    }
+}
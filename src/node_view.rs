@@ -0,0 +1,294 @@
+use counters;
+use redis_tools::decode_hash;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FieldType {
+    Integer,
+    Number,
+    Boolean,
+    Text,
+}
+
+impl FieldType {
+    fn name(&self) -> &'static str {
+        match self {
+            FieldType::Integer => "integer",
+            FieldType::Number => "number",
+            FieldType::Boolean => "boolean",
+            FieldType::Text => "string",
+        }
+    }
+
+    /* stored text back to its typed value */
+    fn parse(&self, s: &str) -> Option<Value> {
+        match self {
+            FieldType::Integer => s
+                .parse::<i64>()
+                .map(Value::from)
+                .or_else(|_| s.parse::<u64>().map(Value::from))
+                .ok(),
+            FieldType::Number => s.parse::<f64>().ok().and_then(|n| {
+                if n.is_finite() {
+                    Some(Value::from(n))
+                } else {
+                    None
+                }
+            }),
+            FieldType::Boolean => s.parse::<bool>().ok().map(Value::from),
+            FieldType::Text => Some(Value::from(s)),
+        }
+    }
+}
+
+use self::FieldType::*;
+
+/* stored node fields, as written by `/update` and joined by `add_reachability` */
+static FIELDS: &[(&str, FieldType)] = &[
+    ("cliid", Text),
+    ("sessid", Text),
+    ("ip", Text),
+    ("timestamp", Integer),
+    ("clock_skew", Number),
+    ("clock_skewed", Boolean),
+    ("reachable", Boolean),
+    ("last_reachability_check", Integer),
+    ("net", Text),
+    ("version", Text),
+    ("protocol_version_p2p", Text),
+    ("protocol_version_task", Text),
+    ("start_port", Integer),
+    ("end_port", Integer),
+    ("estimated_blender_performance", Text),
+    ("estimated_lux_performance", Text),
+    ("estimated_performance", Number),
+    ("max_memory_size", Integer),
+    ("max_price", Integer),
+    ("min_price", Integer),
+    ("max_resource_size", Integer),
+    ("node_name", Text),
+    ("num_cores", Integer),
+    ("hardware_preset_name", Text),
+    ("accept_tasks", Boolean),
+    ("use_upnp", Boolean),
+    ("use_ipv6", Boolean),
+    ("enable_talkback", Boolean),
+    ("opt_peer_num", Integer),
+    ("os", Text),
+    ("os_system", Text),
+    ("os_release", Text),
+    ("os_version", Text),
+    ("os_windows_edition", Text),
+    ("os_linux_distribution", Text),
+    ("known_tasks", Integer),
+    ("supported_tasks", Integer),
+    ("tasks_requested", Integer),
+    ("tasks_with_errors", Integer),
+    ("tasks_with_timeout", Integer),
+    ("completed", Integer),
+    ("rs_tasks_cnt", Integer),
+    ("rs_finished_task_cnt", Integer),
+    ("rs_requested_subtasks_cnt", Integer),
+    ("rs_collected_results_cnt", Integer),
+    ("rs_verified_results_cnt", Integer),
+    ("rs_timed_out_subtasks_cnt", Integer),
+    ("rs_not_downloadable_subtasks_cnt", Integer),
+    ("rs_failed_subtasks_cnt", Integer),
+    ("rs_work_offers_cnt", Integer),
+    ("rs_finished_ok_cnt", Integer),
+    ("rs_finished_ok_total_time", Number),
+    ("rs_finished_with_failures_cnt", Integer),
+    ("rs_finished_with_failures_total_time", Number),
+    ("rs_failed_cnt", Integer),
+    ("rs_failed_total_time", Number),
+    ("nvgpu_is_supported", Boolean),
+    ("requestor_payment_cnt", Integer),
+    ("requestor_payment_delay_avg", Number),
+    ("requestor_payment_delay_sum", Number),
+    ("requestor_subtask_timeout_mag", Integer),
+    ("requestor_subtask_price_mag", Integer),
+    ("requestor_velocity_timeout", Integer),
+    ("requestor_velocity_comp_time", Integer),
+    ("provider_wtct_cnt", Integer),
+    ("provider_ttc_cnt", Integer),
+    ("provider_wtct_to_ttc_delay_sum", Integer),
+    ("provider_wtct_to_ttc_cnt", Integer),
+    ("provider_sra_cnt", Integer),
+    ("provider_srr_cnt", Integer),
+    ("provider_income_assigned_sum", Integer),
+    ("provider_income_completed_sum", Integer),
+    ("provider_income_paid_sum", Integer),
+    ("subtasks_success_ratio", Number),
+    ("rs_finished_ok_avg_time", Number),
+    ("provider_wtct_to_ttc_delay_avg", Number),
+    ("provider_income_paid_ratio", Number),
+];
+
+/* counter bookkeeping, see `counters::CounterGroup::track`: fields of every counter... */
+static COUNTER_SUFFIXES: &[(&str, FieldType)] = &[
+    ("_lifetime", Integer),
+    ("_base", Integer),
+    ("_per_hour", Number),
+];
+
+/* ...and of every group */
+static GROUP_SUFFIXES: &[(&str, FieldType)] =
+    &[("_resets", Integer), ("_at", Integer), ("_sessid", Text)];
+
+fn suffixed_type(field: &str, name: &str, suffixes: &[(&str, FieldType)]) -> Option<FieldType> {
+    if !field.starts_with(name) {
+        return None;
+    }
+    suffixes
+        .iter()
+        .find(|(suffix, _)| &field[name.len()..] == *suffix)
+        .map(|(_, field_type)| *field_type)
+}
+
+fn bookkeeping_type(field: &str) -> Option<FieldType> {
+    counters::GROUPS
+        .iter()
+        .filter_map(|group| {
+            suffixed_type(field, group.name, GROUP_SUFFIXES).or_else(|| {
+                group
+                    .counters
+                    .iter()
+                    .filter_map(|counter| suffixed_type(field, counter, COUNTER_SUFFIXES))
+                    .next()
+            })
+        })
+        .next()
+}
+
+fn bookkeeping_fields() -> Vec<(String, FieldType)> {
+    let mut fields = Vec::new();
+    for group in counters::GROUPS {
+        for (suffix, field_type) in GROUP_SUFFIXES {
+            fields.push((format!("{}{}", group.name, suffix), *field_type));
+        }
+        for counter in group.counters {
+            for (suffix, field_type) in COUNTER_SUFFIXES {
+                fields.push((format!("{}{}", counter, suffix), *field_type));
+            }
+        }
+    }
+    fields
+}
+
+fn field_type(field: &str) -> Option<FieldType> {
+    FIELDS
+        .iter()
+        .find(|(name, _)| *name == field)
+        .map(|(_, field_type)| *field_type)
+        .or_else(|| bookkeeping_type(field))
+}
+
+/// A stored node with its fields re-typed as described by `schema()`, served by `/v2/nodes`.
+/// Values that don't parse as their declared type are left out, unknown fields are passed
+/// through as stored.
+#[derive(Serialize, Debug)]
+pub struct NodeView(Map<String, Value>);
+
+impl NodeView {
+    pub fn from_hash(hash: HashMap<String, String>) -> Self {
+        NodeView(
+            decode_hash(hash)
+                .into_iter()
+                .filter_map(|(key, value)| {
+                    let value = match (value, field_type(&key)) {
+                        (Value::String(s), Some(field_type)) => match field_type.parse(&s) {
+                            Some(value) => value,
+                            None => {
+                                debug!("dropping {}={:?}, not a {}", key, s, field_type.name());
+                                return None;
+                            }
+                        },
+                        (value, _) => value,
+                    };
+                    Some((key, value))
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Schema of a single node.
+pub fn node_schema() -> Value {
+    let properties: Map<String, Value> = FIELDS
+        .iter()
+        .map(|(name, field_type)| (name.to_string(), *field_type))
+        .chain(bookkeeping_fields())
+        .map(|(name, field_type)| (name, json!({ "type": field_type.name() })))
+        .collect();
+
+    json!({
+        "type": "object",
+        "required": ["cliid", "timestamp"],
+        "properties": properties,
+        "additionalProperties": true
+    })
+}
+
+/// JSON Schema of the `/v2/nodes` response.
+pub fn schema() -> Value {
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "nodes",
+        "description": "active nodes, as served by /v2/nodes",
+        "type": "array",
+        "items": { "$ref": "#/definitions/node" },
+        "definitions": { "node": node_schema() }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retyped_fields() {
+        let hash: HashMap<String, String> = vec![
+            ("cliid", "abc"),
+            ("timestamp", "1500000000000"),
+            ("num_cores", "4"),
+            ("estimated_performance", "2220.5"),
+            ("nvgpu_is_supported", "true"),
+            ("node_name", "123"),
+            ("completed_lifetime", "17"),
+            ("completed_per_hour", "2.5"),
+            ("max_price", "garbage"),
+            ("protocol_versions:json", "{\"p2p\":27}"),
+            ("something_new", "1"),
+            ("download_at", "soon"),
+            ("completed_base", "18446744073709551615"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        let NodeView(node) = NodeView::from_hash(hash);
+        assert_eq!(node["timestamp"], json!(1500000000000u64));
+        assert_eq!(node["num_cores"], json!(4));
+        assert_eq!(node["estimated_performance"], json!(2220.5));
+        assert_eq!(node["nvgpu_is_supported"], json!(true));
+        assert_eq!(node["node_name"], json!("123"));
+        assert_eq!(node["completed_lifetime"], json!(17));
+        assert_eq!(node["completed_per_hour"], json!(2.5));
+        assert!(!node.contains_key("max_price"));
+        assert_eq!(node["protocol_versions"], json!({"p2p": 27}));
+        assert_eq!(node["something_new"], json!("1"));
+        assert_eq!(node["download_at"], json!("soon"));
+        assert_eq!(node["completed_base"], json!(u64::MAX));
+    }
+
+    #[test]
+    fn schema_covers_fields() {
+        let schema = schema();
+        let node = &schema["definitions"]["node"];
+        assert_eq!(node["properties"]["num_cores"]["type"], "integer");
+        assert_eq!(node["properties"]["accept_tasks"]["type"], "boolean");
+        assert_eq!(node["properties"]["completed_per_hour"]["type"], "number");
+        assert_eq!(node["properties"]["stats_sessid"]["type"], "string");
+    }
+}
@@ -0,0 +1,269 @@
+use actix_web::{App, HttpResponse};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{self, Map, Value};
+
+/// OpenAPI document under construction. Modules add the routes they mount,
+/// request and response schemas are derived from their types.
+pub struct ApiDoc {
+    gen: SchemaGenerator,
+    paths: Map<String, Value>,
+}
+
+impl ApiDoc {
+    fn new() -> Self {
+        ApiDoc {
+            gen: SchemaGenerator::new(SchemaSettings::openapi3()),
+            paths: Map::new(),
+        }
+    }
+
+    /// Reference to the schema of `T`, registered under `components/schemas`.
+    pub fn schema<T: JsonSchema>(&mut self) -> Value {
+        serde_json::to_value(self.gen.subschema_for::<T>()).unwrap_or(Value::Null)
+    }
+
+    /// Adds a schema not derived from a type, e.g. a hand written JSON Schema.
+    pub fn add_schema(&mut self, name: &str, schema: Value) -> Value {
+        let schema = match serde_json::from_value(schema) {
+            Ok(schema) => schema,
+            Err(e) => {
+                error!("schema {} is not a JSON Schema: {}", name, e);
+                Schema::Bool(true)
+            }
+        };
+        self.gen.definitions_mut().insert(name.to_string(), schema);
+        json!({ "$ref": format!("#/components/schemas/{}", name) })
+    }
+
+    pub fn add(&mut self, path: &str, method: &str, operation: Value) {
+        if let Value::Object(ref mut methods) = *self
+            .paths
+            .entry(path.to_string())
+            .or_insert_with(|| json!({}))
+        {
+            methods.insert(method.to_string(), operation);
+        }
+    }
+
+    fn into_document(mut self) -> Value {
+        let schemas = serde_json::to_value(self.gen.take_definitions()).unwrap_or(Value::Null);
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": "golem-monitor",
+                "description": "Collects reports of Golem nodes and serves their current state.",
+                "version": env!("CARGO_PKG_VERSION")
+            },
+            "paths": self.paths,
+            "components": { "schemas": schemas }
+        })
+    }
+}
+
+/// Required `application/json` request body.
+pub fn json_body(schema: Value) -> Value {
+    json!({
+        "required": true,
+        "content": { "application/json": { "schema": schema } }
+    })
+}
+
+pub fn response(description: &str, content_type: &str, schema: Value) -> Value {
+    let mut content = Map::new();
+    content.insert(content_type.to_string(), json!({ "schema": schema }));
+    json!({ "description": description, "content": content })
+}
+
+/// Describes every route compiled in.
+pub fn document() -> Value {
+    let mut doc = ApiDoc::new();
+
+    #[cfg(feature = "stats_update")]
+    ::stats_update::describe(&mut doc);
+    #[cfg(feature = "list_nodes")]
+    ::list_nodes::describe(&mut doc);
+    #[cfg(feature = "pingme")]
+    ::pingme::describe(&mut doc);
+    ::events::describe(&mut doc);
+    ::health::describe(&mut doc);
+
+    doc.add(
+        "/openapi.json",
+        "get",
+        json!({
+            "summary": "this document",
+            "responses": {
+                "200": response("OpenAPI 3 document", "application/json", json!({ "type": "object" }))
+            }
+        }),
+    );
+
+    doc.into_document()
+}
+
+pub fn route_openapi() -> impl Fn(App) -> App {
+    let document = document().to_string();
+
+    move |app: App| {
+        let document = document.clone();
+        app.resource("/openapi.json", move |r| {
+            r.get().f(move |_| {
+                HttpResponse::Ok()
+                    .content_type("application/json")
+                    .header("cache-control", "public, max-age=3600")
+                    .body(document.clone())
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /* the subset of OpenAPI schema objects generated by schemars */
+    fn validate(doc: &Value, schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+        if let Some(reference) = schema["$ref"].as_str() {
+            let name = reference.trim_start_matches("#/components/schemas/");
+            return validate(doc, &doc["components"]["schemas"][name], value, path);
+        }
+        if value.is_null() && schema["nullable"] == json!(true) {
+            return Ok(());
+        }
+        if let Some(expected) = schema["type"].as_str() {
+            let matches = match (expected, value) {
+                ("object", Value::Object(_))
+                | ("array", Value::Array(_))
+                | ("string", Value::String(_))
+                | ("boolean", Value::Bool(_)) => true,
+                ("number", Value::Number(_)) => true,
+                ("integer", Value::Number(n)) => n.is_i64() || n.is_u64(),
+                _ => false,
+            };
+            if !matches {
+                return Err(format!("{}: expected {}, got {}", path, expected, value));
+            }
+        }
+        if let Some(allowed) = schema["enum"].as_array() {
+            if !allowed.contains(value) {
+                return Err(format!("{}: {} not in {:?}", path, value, allowed));
+            }
+        }
+        for required in schema["required"]
+            .as_array()
+            .into_iter()
+            .flat_map(|r| r.iter())
+        {
+            let key = required.as_str().unwrap();
+            if value.get(key).is_none() {
+                return Err(format!("{}: missing {}", path, key));
+            }
+        }
+        if let (Some(properties), Value::Object(fields)) = (schema["properties"].as_object(), value)
+        {
+            for (key, field) in fields {
+                let path = format!("{}.{}", path, key);
+                match properties.get(key) {
+                    Some(property) => validate(doc, property, field, &path)?,
+                    None if schema["additionalProperties"] == json!(false) => {
+                        return Err(format!("{}: unexpected", path))
+                    }
+                    None if schema["additionalProperties"].is_object() => {
+                        validate(doc, &schema["additionalProperties"], field, &path)?
+                    }
+                    None => (),
+                }
+            }
+        }
+        if let (Some(items), Value::Array(elements)) = (schema.get("items"), value) {
+            for (i, element) in elements.iter().enumerate() {
+                validate(doc, items, element, &format!("{}[{}]", path, i))?;
+            }
+        }
+        for sub in schema["allOf"]
+            .as_array()
+            .into_iter()
+            .flat_map(|s| s.iter())
+        {
+            validate(doc, sub, value, path)?;
+        }
+        for (keyword, exactly_one) in &[("anyOf", false), ("oneOf", true)] {
+            if let Some(subs) = schema[*keyword].as_array() {
+                let results: Vec<_> = subs
+                    .iter()
+                    .map(|sub| validate(doc, sub, value, path))
+                    .collect();
+                let matched = results.iter().filter(|r| r.is_ok()).count();
+                if matched == 0 || (*exactly_one && matched > 1) {
+                    return Err(format!(
+                        "{}: {} of {} matched: {:?}",
+                        path, matched, keyword, results
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn lists_routes() {
+        let doc = document();
+        assert_eq!(doc["openapi"], "3.0.3");
+        assert!(doc["paths"]["/openapi.json"]["get"].is_object());
+        assert!(doc["paths"]["/v1/events"]["get"].is_object());
+        #[cfg(feature = "stats_update")]
+        assert!(doc["paths"]["/update"]["post"].is_object());
+        #[cfg(feature = "list_nodes")]
+        assert!(doc["paths"]["/v2/nodes"]["get"].is_object());
+    }
+
+    #[test]
+    #[cfg(feature = "stats_update")]
+    fn request_schema() {
+        let doc = document();
+        let schema = &doc["paths"]["/update"]["post"]["requestBody"]["content"]["application/json"]
+            ["schema"];
+        assert_eq!(
+            schema["$ref"],
+            "#/components/schemas/Envelope_for_GolemRequest"
+        );
+
+        let schemas = &doc["components"]["schemas"];
+        let envelope = &schemas["Envelope_for_GolemRequest"];
+        assert_eq!(envelope["required"], json!(["data", "proto_ver"]));
+        assert_eq!(envelope["properties"]["proto_ver"]["type"], "integer");
+
+        let stats = schemas["GolemRequest"]["oneOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|variant| variant["properties"]["type"]["enum"] == json!(["Stats"]))
+            .unwrap();
+        assert_eq!(stats["properties"]["computed_tasks"]["type"], "integer");
+        assert_eq!(stats["properties"]["sessid"]["nullable"], true);
+        assert_eq!(stats["additionalProperties"], true);
+    }
+
+    #[test]
+    #[cfg(feature = "stats_update")]
+    fn fixtures_match_request_schema() {
+        let doc = document();
+        let schema = &doc["paths"]["/update"]["post"]["requestBody"]["content"]["application/json"]
+            ["schema"];
+
+        let mut checked = 0;
+        for entry in fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/test")).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let fixture: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+            validate(&doc, schema, &fixture, &path.display().to_string()).unwrap();
+            checked += 1;
+        }
+        assert!(checked > 0);
+    }
+}
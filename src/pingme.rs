@@ -1,25 +1,71 @@
 use super::get_client_ip;
-use actix::Arbiter;
+use actix::prelude::*;
 use actix_web::{self, AsyncResponder, HttpMessage, HttpRequest, HttpResponse};
 use futures::future;
 use futures::prelude::*;
+use libc;
 use nom::AsBytes;
+use openapi::{self, ApiDoc};
+use redis_tools::{AsRedisHandle, Redis};
+use schemars::JsonSchema;
+use std::io;
 use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tokio_core::net::TcpStream;
+use tokio_core::net::{TcpStream, UdpSocket};
 use tokio_core::reactor;
+use tokio_io;
+use updater::{UpdateRedis, UpdateVal, Updater};
 use url::form_urlencoded::parse;
 
-const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
-const MAX_PORTS: usize = 5;
+const MAX_DATAGRAM_SIZE: usize = 2048;
+
+#[derive(Clone, Debug)]
+pub struct PingMeConfig {
+    /// How long a single port probe may take before it is reported as `timeout`.
+    pub timeout: Duration,
+    /// Maximum number of ports a single request may ask for.
+    pub max_ports: usize,
+    /// Sent right after the connection is established (tcp) or as the datagram (udp).
+    pub banner_send: Vec<u8>,
+    /// Expected reply to `banner_send` on `tcp-handshake`.
+    pub banner_expect: Vec<u8>,
+    /// How long stored results are kept.
+    pub result_ttl: Duration,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+enum Probe {
+    #[default]
+    TcpConnect,
+    TcpHandshake,
+    UdpEcho,
+}
+
+impl FromStr for Probe {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
+        match s {
+            "tcp-connect" => Ok(Probe::TcpConnect),
+            "tcp-handshake" => Ok(Probe::TcpHandshake),
+            "udp-echo" => Ok(Probe::UdpEcho),
+            _ => Err(()),
+        }
+    }
+}
 
-#[derive(Deserialize, Debug, PartialEq)]
+#[derive(Deserialize, JsonSchema, Debug, PartialEq)]
 #[serde(rename_all = "lowercase")]
 struct PingMe {
     timestamp: f64,
     port: Option<u16>,
     #[serde(default)]
     ports: Vec<u16>,
+    #[serde(default)]
+    probe: Probe,
+    cliid: Option<String>,
 }
 
 impl PingMe {
@@ -32,7 +78,7 @@ impl PingMe {
     }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, JsonSchema, Debug)]
 struct PingMeResult {
     success: bool,
     description: String,
@@ -40,44 +86,175 @@ struct PingMeResult {
     time_diff: f64,
 }
 
+/// Ping-me result as persisted in redis under `pingme.<ip>` and `pingme.<cliid>`.
 #[derive(Serialize, Debug)]
+struct StoredPingMeResult<'a> {
+    timestamp: u64,
+    #[serde(flatten)]
+    result: &'a PingMeResult,
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
 struct PortStatus {
     port: u16,
+    probe: Probe,
     is_open: bool,
     description: &'static str,
+    code: u8,
+}
+
+impl PortStatus {
+    fn new(port: u16, probe: Probe, state: PortState) -> Self {
+        PortStatus {
+            port,
+            probe,
+            is_open: state == PortState::Open,
+            description: state.description(),
+            code: state.code(),
+        }
+    }
+}
+
+/// Outcome of a single port probe. Codes are part of the API, do not renumber.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum PortState {
+    Open,
+    Refused,
+    HostUnreachable,
+    NetworkUnreachable,
+    Timeout,
+    Reset,
+    BadBanner,
+    Unreachable,
+}
+
+impl PortState {
+    fn description(&self) -> &'static str {
+        match self {
+            PortState::Open => "open",
+            PortState::Refused => "refused",
+            PortState::HostUnreachable => "host-unreachable",
+            PortState::NetworkUnreachable => "network-unreachable",
+            PortState::Timeout => "timeout",
+            PortState::Reset => "reset",
+            PortState::BadBanner => "bad-banner",
+            PortState::Unreachable => "unreachable",
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match self {
+            PortState::Open => 0,
+            PortState::Refused => 1,
+            PortState::HostUnreachable => 2,
+            PortState::NetworkUnreachable => 3,
+            PortState::Timeout => 4,
+            PortState::Reset => 5,
+            PortState::BadBanner => 6,
+            PortState::Unreachable => 99,
+        }
+    }
+}
+
+impl<'a> From<&'a io::Error> for PortState {
+    fn from(err: &'a io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => PortState::Refused,
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => PortState::Reset,
+            io::ErrorKind::TimedOut => PortState::Timeout,
+            io::ErrorKind::UnexpectedEof => PortState::BadBanner,
+            // std has no stable kinds for these, look at errno instead
+            _ => match err.raw_os_error() {
+                Some(libc::EHOSTUNREACH) => PortState::HostUnreachable,
+                Some(libc::ENETUNREACH) => PortState::NetworkUnreachable,
+                _ => PortState::Unreachable,
+            },
+        }
+    }
+}
+
+fn probe_tcp_connect(addr: SocketAddr) -> Box<Future<Item = PortState, Error = io::Error>> {
+    Box::new(TcpStream::connect(&addr, Arbiter::handle()).map(|_tcp| PortState::Open))
+}
+
+fn probe_tcp_handshake(
+    addr: SocketAddr,
+    config: &PingMeConfig,
+) -> Box<Future<Item = PortState, Error = io::Error>> {
+    let banner_send = config.banner_send.clone();
+    let banner_expect = config.banner_expect.clone();
+
+    Box::new(
+        TcpStream::connect(&addr, Arbiter::handle())
+            .and_then(move |tcp| tokio_io::io::write_all(tcp, banner_send))
+            .and_then(move |(tcp, _)| {
+                let buf = vec![0u8; banner_expect.len()];
+                tokio_io::io::read_exact(tcp, buf).map(move |(_tcp, banner)| {
+                    if banner == banner_expect {
+                        PortState::Open
+                    } else {
+                        PortState::BadBanner
+                    }
+                })
+            }),
+    )
+}
+
+fn probe_udp_echo(
+    addr: SocketAddr,
+    config: &PingMeConfig,
+) -> Box<Future<Item = PortState, Error = io::Error>> {
+    let local_addr = match addr {
+        SocketAddr::V4(_) => SocketAddr::new([0, 0, 0, 0].into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new([0u16, 0, 0, 0, 0, 0, 0, 0].into(), 0),
+    };
+
+    // connected socket, so an ICMP port unreachable is reported back as "refused"
+    let socket = match UdpSocket::bind(&local_addr, Arbiter::handle())
+        .and_then(|socket| socket.connect(&addr).map(|_| socket))
+    {
+        Ok(socket) => socket,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    Box::new(
+        socket
+            .send_dgram(config.banner_send.clone(), addr)
+            .and_then(|(socket, banner)| {
+                socket.recv_dgram(vec![0u8; MAX_DATAGRAM_SIZE]).map(
+                    move |(_socket, buf, n, _from)| {
+                        if &buf[..n] == banner.as_slice() {
+                            PortState::Open
+                        } else {
+                            PortState::BadBanner
+                        }
+                    },
+                )
+            }),
+    )
 }
 
 fn ping_address(
     addr: &IpAddr,
     port: u16,
+    probe: Probe,
+    config: &PingMeConfig,
 ) -> Box<Future<Item = PortStatus, Error = actix_web::Error>> {
     let addr = SocketAddr::new(*addr, port);
 
-    let timeout = reactor::Timeout::new(DEFAULT_TIMEOUT, Arbiter::handle())
+    let timeout = reactor::Timeout::new(config.timeout, Arbiter::handle())
         .unwrap()
-        .and_then(move |_| {
-            Ok(PortStatus {
-                port,
-                is_open: false,
-                description: "timeout",
-            })
-        });
-
-    let ping = TcpStream::connect(&addr, Arbiter::handle())
-        .and_then(move |_tcp| {
-            Ok(PortStatus {
-                port,
-                is_open: true,
-                description: "open",
-            })
-        })
-        .or_else(move |_err| {
-            Ok(PortStatus {
-                port,
-                is_open: false,
-                description: "unreachable",
-            })
-        });
+        .and_then(move |_| Ok(PortStatus::new(port, probe, PortState::Timeout)));
+
+    let ping = match probe {
+        Probe::TcpConnect => probe_tcp_connect(addr),
+        Probe::TcpHandshake => probe_tcp_handshake(addr, config),
+        Probe::UdpEcho => probe_udp_echo(addr, config),
+    }
+    .and_then(move |state| Ok(PortStatus::new(port, probe, state)))
+    .or_else(move |err| Ok(PortStatus::new(port, probe, PortState::from(&err))));
 
     Box::new(
         timeout
@@ -92,9 +269,13 @@ fn ping_address(
 fn ping_multi(
     addr: &IpAddr,
     ports: &Vec<u16>,
+    probe: Probe,
+    config: &PingMeConfig,
 ) -> Box<Future<Item = Vec<PortStatus>, Error = actix_web::Error>> {
-    let p: Vec<Box<Future<Item = PortStatus, Error = actix_web::Error>>> =
-        ports.iter().map(|port| ping_address(addr, *port)).collect();
+    let p: Vec<Box<Future<Item = PortStatus, Error = actix_web::Error>>> = ports
+        .iter()
+        .map(|port| ping_address(addr, *port, probe, config))
+        .collect();
 
     Box::new(future::join_all(p))
 }
@@ -106,6 +287,64 @@ fn duration_to_secs(d: &Duration) -> f64 {
     secs + milis * 0.001f64
 }
 
+fn to_millis(t: SystemTime) -> u64 {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+/* `cliid` is taken from the request as is, the result is stored under it only when
+ * the node last reported from the probed address */
+fn store_result(
+    updater: &Addr<Unsync, Updater>,
+    redis: &Redis,
+    ip: IpAddr,
+    cliid: Option<String>,
+    checked_at: SystemTime,
+    result: &PingMeResult,
+    ttl: Duration,
+) {
+    let stored = StoredPingMeResult {
+        timestamp: to_millis(checked_at),
+        result,
+    };
+    let value = match serde_json::to_string(&stored) {
+        Ok(value) => value,
+        Err(e) => return warn!("unable to store ping-me result: {}", e),
+    };
+
+    let updater = updater.clone();
+    let store = move |key: String| {
+        updater.do_send(UpdateRedis::UpdateRedisVal(UpdateVal {
+            collection: "pingme".to_string(),
+            key,
+            value: value.clone(),
+            ttl: Some(ttl.as_secs()),
+        }))
+    };
+    store(ip.to_string());
+
+    if let Some(cliid) = cliid {
+        let ip = ip.to_string();
+        Arbiter::handle().spawn(
+            redis
+                .as_redis_handle()
+                .get_hash(format!("nodeinfo.{}", cliid))
+                .then(move |r| {
+                    match r {
+                        Ok(ref node) if node.get("ip") == Some(&ip) => store(cliid),
+                        Ok(_) => debug!(
+                            "ping-me of {} not stored for {}, address differs",
+                            ip, cliid
+                        ),
+                        Err(e) => warn!("unable to look up the address of {}: {}", cliid, e),
+                    }
+                    Ok(())
+                }),
+        );
+    }
+}
+
 fn time_diff(base: SystemTime, other: SystemTime) -> f64 {
     if base < other {
         -duration_to_secs(&other.duration_since(base).unwrap())
@@ -114,18 +353,34 @@ fn time_diff(base: SystemTime, other: SystemTime) -> f64 {
     }
 }
 
-pub fn ping_me(r: HttpRequest) -> Box<Future<Item = HttpResponse, Error = actix_web::Error>> {
+pub fn ping_me(
+    r: HttpRequest,
+    config: &PingMeConfig,
+    updater: &Addr<Unsync, Updater>,
+    redis: &Redis,
+) -> Box<Future<Item = HttpResponse, Error = actix_web::Error>> {
     let system_time = SystemTime::now();
     let client_ip = get_client_ip(&r);
+    let config = config.clone();
+    let updater = updater.clone();
+    let redis = redis.clone();
+    let result_ttl = config.result_ttl;
+    let max_ports = config.max_ports;
+    let banner_expect_empty = config.banner_expect.is_empty();
 
     r.body()
-        .and_then(|b| Ok(parse_url_params(b.as_bytes())))
         .map_err(|e| actix_web::error::ErrorBadRequest(format!("{}", e)))
-        .and_then(|b: PingMe| {
+        .and_then(|b| parse_url_params(b.as_bytes()).map_err(actix_web::error::ErrorBadRequest))
+        .and_then(move |b: PingMe| {
             let ports = b.ports();
 
-            if ports.len() > MAX_PORTS {
+            if ports.len() > max_ports {
                 future::err(actix_web::error::ErrorBadRequest("too many ports"))
+            } else if b.probe == Probe::TcpHandshake && banner_expect_empty {
+                // without a banner to expect the handshake is only a connect
+                future::err(actix_web::error::ErrorBadRequest(
+                    "tcp-handshake probe not configured",
+                ))
             } else {
                 future::ok((b, ports))
             }
@@ -134,7 +389,7 @@ pub fn ping_me(r: HttpRequest) -> Box<Future<Item = HttpResponse, Error = actix_
             let timestamp = UNIX_EPOCH + Duration::from_millis((b.timestamp * 1000.0f64) as u64);
 
             let l: Box<Future<Item = Vec<PortStatus>, Error = actix_web::Error>> = match client_ip {
-                Some(ref addr) => ping_multi(&addr, &ports),
+                Some(ref addr) => ping_multi(addr, &ports, b.probe, &config),
                 _ => Box::new(future::err(actix_web::error::ErrorInternalServerError(
                     "source address not valid",
                 ))),
@@ -166,6 +421,18 @@ pub fn ping_me(r: HttpRequest) -> Box<Future<Item = HttpResponse, Error = actix_
                     client_ip, ping_me_result
                 );
 
+                if let Some(ip) = client_ip {
+                    store_result(
+                        &updater,
+                        &redis,
+                        ip,
+                        b.cliid,
+                        system_time,
+                        &ping_me_result,
+                        result_ttl,
+                    );
+                }
+
                 Ok(HttpResponse::Ok().json(ping_me_result).into())
             })
         })
@@ -179,11 +446,13 @@ pub fn ping_me(r: HttpRequest) -> Box<Future<Item = HttpResponse, Error = actix_
         .responder()
 }
 
-fn parse_url_params(input: &[u8]) -> PingMe {
+fn parse_url_params(input: &[u8]) -> Result<PingMe, String> {
     let mut ping_me = PingMe {
         timestamp: 0f64,
         port: None,
         ports: Vec::new(),
+        probe: Probe::default(),
+        cliid: None,
     };
     for (k, v) in parse(input) {
         match k.as_ref() {
@@ -198,11 +467,34 @@ fn parse_url_params(input: &[u8]) -> PingMe {
                 }
             }
             "port" => ping_me.port = v.parse().ok(),
+            "probe" => {
+                ping_me.probe = v.parse().map_err(|_| format!("unknown probe {}", v))?;
+            }
+            "cliid" => ping_me.cliid = Some(v.into_owned()),
             _ => println!("unknown param {}={}", k, v),
         }
     }
 
-    ping_me
+    Ok(ping_me)
+}
+
+/// `/ping-me` in the OpenAPI document.
+pub fn describe(doc: &mut ApiDoc) {
+    let request = doc.schema::<PingMe>();
+    let result = doc.schema::<PingMeResult>();
+
+    doc.add(
+        "/ping-me",
+        "post",
+        json!({
+            "summary": "probes ports at the address of the caller",
+            "requestBody": openapi::json_body(request),
+            "responses": {
+                "200": openapi::response("probe results", "application/json", result),
+                "400": { "description": "no ports, too many ports, unknown probe or tcp-handshake without a configured banner" }
+            }
+        }),
+    );
 }
 
 #[cfg(test)]
@@ -215,16 +507,70 @@ mod tests {
         let p: PingMe = serde_json::from_str("{\"port\": 2020, \"timestamp\": 12.0}").unwrap();
         assert_eq!(p.port.unwrap(), 2020);
         assert!(p.timestamp > 11.0);
+        assert_eq!(p.probe, Probe::TcpConnect);
+    }
+
+    #[test]
+    fn test_parse_probe() {
+        let p: PingMe = serde_json::from_str(
+            "{\"ports\": [40102], \"timestamp\": 1.0, \"probe\": \"udp-echo\"}",
+        )
+        .unwrap();
+        assert_eq!(p.probe, Probe::UdpEcho);
+
+        let ping_me =
+            parse_url_params("ports=40102&probe=tcp-handshake&timestamp=1".as_bytes()).unwrap();
+        assert_eq!(
+            ping_me,
+            PingMe {
+                ports: vec![40102],
+                port: None,
+                probe: Probe::TcpHandshake,
+                cliid: None,
+                timestamp: 1f64,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_unknown_probe() {
+        assert!(parse_url_params("ports=40102&probe=icmp&timestamp=1".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_port_state_from_error() {
+        let state = |kind| PortState::from(&io::Error::new(kind, "test"));
+        assert_eq!(state(io::ErrorKind::ConnectionRefused), PortState::Refused);
+        assert_eq!(state(io::ErrorKind::ConnectionReset), PortState::Reset);
+        assert_eq!(state(io::ErrorKind::TimedOut), PortState::Timeout);
+        assert_eq!(state(io::ErrorKind::UnexpectedEof), PortState::BadBanner);
+        assert_eq!(state(io::ErrorKind::Other), PortState::Unreachable);
+
+        let os_state = |errno| PortState::from(&io::Error::from_raw_os_error(errno));
+        assert_eq!(os_state(libc::ECONNREFUSED), PortState::Refused);
+        assert_eq!(os_state(libc::EHOSTUNREACH), PortState::HostUnreachable);
+        assert_eq!(os_state(libc::ENETUNREACH), PortState::NetworkUnreachable);
+    }
+
+    #[test]
+    fn test_port_status() {
+        let status = PortStatus::new(40102, Probe::TcpConnect, PortState::HostUnreachable);
+        assert!(!status.is_open);
+        assert_eq!(status.description, "host-unreachable");
+        assert_eq!(status.code, 2);
+        assert!(PortStatus::new(40102, Probe::UdpEcho, PortState::Open).is_open);
     }
 
     #[test]
     fn test_empty() {
-        let ping_me = parse_url_params("".as_bytes());
+        let ping_me = parse_url_params("".as_bytes()).unwrap();
         assert_eq!(
             ping_me,
             PingMe {
                 ports: vec![],
                 port: None,
+                probe: Probe::TcpConnect,
+                cliid: None,
                 timestamp: 0f64,
             }
         );
@@ -232,12 +578,14 @@ mod tests {
 
     #[test]
     fn test_timestamp() {
-        let ping_me = parse_url_params("timestamp=3.14".as_bytes());
+        let ping_me = parse_url_params("timestamp=3.14".as_bytes()).unwrap();
         assert_eq!(
             ping_me,
             PingMe {
                 ports: vec![],
                 port: None,
+                probe: Probe::TcpConnect,
+                cliid: None,
                 timestamp: 3.14,
             }
         );
@@ -245,27 +593,39 @@ mod tests {
 
     #[test]
     fn test_parse_single_port() {
-        let ping_me = parse_url_params("port=37&timestamp=7".as_bytes());
+        let ping_me = parse_url_params("port=37&timestamp=7".as_bytes()).unwrap();
         assert_eq!(
             ping_me,
             PingMe {
                 ports: vec![],
                 port: Some(37),
+                probe: Probe::TcpConnect,
+                cliid: None,
                 timestamp: 7f64,
             }
         );
     }
 
+    #[test]
+    fn test_parse_cliid() {
+        let ping_me = parse_url_params("port=40102&cliid=0xab12&timestamp=7".as_bytes()).unwrap();
+        assert_eq!(ping_me.cliid, Some("0xab12".to_string()));
+        assert_eq!(ping_me.port, Some(40102));
+    }
+
     #[test]
     fn test_parse_multiple_ports() {
         let ping_me = parse_url_params(
             "ports=40102&ports=40103&ports=3282&timestamp=1530717930.2452438".as_bytes(),
-        );
+        )
+        .unwrap();
         assert_eq!(
             ping_me,
             PingMe {
                 ports: vec![40102, 40103, 3282],
                 port: None,
+                probe: Probe::TcpConnect,
+                cliid: None,
                 timestamp: 1530717930.2452438,
             }
         );
@@ -275,15 +635,17 @@ mod tests {
     fn test_parse_unknown() {
         let ping_me = parse_url_params(
             "portsa=40102&portsb=40103&ports=3282&timestamp=1530717930.2452438".as_bytes(),
-        );
+        )
+        .unwrap();
         assert_eq!(
             ping_me,
             PingMe {
                 ports: vec![3282],
                 port: None,
+                probe: Probe::TcpConnect,
+                cliid: None,
                 timestamp: 1530717930.2452438,
             }
         );
     }
-
 }
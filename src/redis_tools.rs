@@ -1,8 +1,11 @@
 use actix::prelude::*;
 use actix_redis::{Command, RedisActor, RespError, RespValue};
 use futures::prelude::*;
+use memory::{MemoryStore, MEMORY_ADDRESS};
+use serde_json::{Map, Value};
+use std::cmp;
 use std::collections::HashMap;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub trait RespValueExt: Sized {
     type Error;
@@ -46,14 +49,14 @@ impl RespValueExt for RespValue {
         }
     }
 
-    fn into_vec(self) -> Result<Vec<Self>, Self::Error> {
+    fn into_vec(self) -> Result<Vec<Self>, RespError> {
         match self {
             RespValue::Array(v) => Ok(v),
             _ => Err(RespError::Internal("array expected".into())),
         }
     }
 
-    fn into_i64(self) -> Result<i64, Self::Error> {
+    fn into_i64(self) -> Result<i64, RespError> {
         match self {
             RespValue::Integer(v) => Ok(v),
             _ => Err(RespError::Internal("integer expected".into())),
@@ -61,15 +64,136 @@ impl RespValueExt for RespValue {
     }
 }
 
+static CHANGE_GENERATION_KEY: &str = "nodes.generation";
+static CHANGE_MODIFIED_KEY: &str = "nodes.modified";
+
+/// Bumped on every node write, lets readers tell whether stored nodes have changed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChangeMarker {
+    pub generation: u64,
+    /// millis since epoch
+    pub modified: u64,
+    /// inactivity window the marker was read in, see `expiring`
+    pub window: u64,
+}
+
+impl ChangeMarker {
+    /// Takes the `inactive`-long window `now` falls in into account, so listings that
+    /// drop inactive nodes stop validating once nodes may have expired without a write.
+    pub fn expiring(self, inactive: Option<Duration>, now: SystemTime) -> Self {
+        let inactive = match inactive {
+            Some(inactive) if inactive.as_secs() > 0 => inactive.as_secs() * 1000,
+            _ => return self,
+        };
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1000 + d.subsec_millis() as u64)
+            .unwrap_or(0);
+        let window = now / inactive;
+
+        ChangeMarker {
+            window,
+            modified: cmp::max(self.modified, window * inactive),
+            ..self
+        }
+    }
+
+    pub fn touch_commands(modified: u64) -> Vec<Command> {
+        vec![
+            Command(resp_array!["INCR", CHANGE_GENERATION_KEY]),
+            Command(resp_array![
+                "SET",
+                CHANGE_MODIFIED_KEY,
+                modified.to_string()
+            ]),
+        ]
+    }
+}
+
+/// Node hash fields holding a JSON-encoded array, object or `null` carry this suffix,
+/// any other value is stored as its plain string form.
+pub static JSON_FIELD_SUFFIX: &str = ":json";
+
+pub fn encode_hash_field(key: &str, value: &Value) -> (String, String) {
+    match value {
+        Value::String(s) => (key.to_string(), s.clone()),
+        Value::Number(n) => (key.to_string(), n.to_string()),
+        Value::Bool(b) => (key.to_string(), b.to_string()),
+        _ => (format!("{}{}", key, JSON_FIELD_SUFFIX), value.to_string()),
+    }
+}
+
+/// Reverse of `encode_hash_field`: JSON-encoded fields get their original key and value back,
+/// plain fields stay strings.
+pub fn decode_hash(hash: HashMap<String, String>) -> Map<String, Value> {
+    hash.into_iter()
+        .map(|(key, value)| {
+            if !key.ends_with(JSON_FIELD_SUFFIX) {
+                return (key, Value::String(value));
+            }
+            let decoded = ::serde_json::from_str(&value).unwrap_or_else(|e| {
+                warn!("undecodable hash field {}: {}", key, e);
+                Value::String(value)
+            });
+            (
+                key[..key.len() - JSON_FIELD_SUFFIX.len()].to_string(),
+                decoded,
+            )
+        })
+        .collect()
+}
+
+pub type RedisReply =
+    Box<Future<Item = Result<RespValue, actix_redis::Error>, Error = MailboxError>>;
+
+/// Where commands go: a redis server, or the process-wide `MemoryStore` when the
+/// configured address is `memory`.
+#[derive(Clone)]
+pub enum Redis {
+    Server(Addr<Unsync, RedisActor>),
+    Memory(Addr<Syn, MemoryStore>),
+}
+
+impl Redis {
+    pub fn connect(address: &str) -> Self {
+        if address == MEMORY_ADDRESS {
+            Redis::Memory(Arbiter::system_registry().get::<MemoryStore>())
+        } else {
+            Redis::Server(RedisActor::start(address.to_string()))
+        }
+    }
+
+    pub fn send(&self, cmd: Command) -> RedisReply {
+        match self {
+            Redis::Server(actor) => Box::new(actor.send(cmd)),
+            Redis::Memory(store) => Box::new(store.send(cmd)),
+        }
+    }
+
+    pub fn send_timeout(&self, cmd: Command, timeout: Duration) -> RedisReply {
+        match self {
+            Redis::Server(actor) => Box::new(actor.send(cmd).timeout(timeout)),
+            Redis::Memory(store) => Box::new(store.send(cmd).timeout(timeout)),
+        }
+    }
+
+    pub fn do_send(&self, cmd: Command) {
+        match self {
+            Redis::Server(actor) => actor.do_send(cmd),
+            Redis::Memory(store) => store.do_send(cmd),
+        }
+    }
+}
+
 pub trait AsRedisHandle {
     fn as_redis_handle(&self) -> RedisHandle<'_>;
 }
 
 pub struct RedisHandle<'a> {
-    actor: &'a Addr<Unsync, RedisActor>,
+    actor: &'a Redis,
 }
 
-impl AsRedisHandle for Addr<Unsync, RedisActor> {
+impl AsRedisHandle for Redis {
     fn as_redis_handle(&self) -> RedisHandle<'_> {
         RedisHandle { actor: self }
     }
@@ -84,16 +208,17 @@ impl<'a> RedisHandle<'a> {
         let actor = self.actor.clone();
 
         scan_with_query(move |cursor| {
-            actor
-                .send(Command(resp_array![
+            actor.send_timeout(
+                Command(resp_array![
                     "SCAN",
                     cursor.to_string(),
                     "MATCH",
                     pattern.clone(),
                     "COUNT",
                     count.to_string()
-                ]))
-                .timeout(Duration::from_secs(2))
+                ]),
+                Duration::from_secs(2),
+            )
         })
     }
 
@@ -105,14 +230,16 @@ impl<'a> RedisHandle<'a> {
         let actor = self.actor.clone();
         scan_with_query(move |cursor| {
             actor
-                .send(Command(resp_array![
-                    "SSCAN",
-                    set_key.to_string(),
-                    cursor.to_string(),
-                    "COUNT",
-                    count.to_string()
-                ]))
-                .timeout(Duration::from_secs(2))
+                .send_timeout(
+                    Command(resp_array![
+                        "SSCAN",
+                        set_key.to_string(),
+                        cursor.to_string(),
+                        "COUNT",
+                        count.to_string()
+                    ]),
+                    Duration::from_secs(2),
+                )
                 .map_err(|e| {
                     match e {
                         MailboxError::Timeout => error!("timeout on scan set"),
@@ -128,8 +255,7 @@ impl<'a> RedisHandle<'a> {
         key: String,
     ) -> impl Future<Item = HashMap<String, String>, Error = RespError> {
         self.actor
-            .send(Command(resp_array!["HGETALL", key]))
-            .timeout(Duration::from_secs(5))
+            .send_timeout(Command(resp_array!["HGETALL", key]), Duration::from_secs(5))
             .map_err(|_e| RespError::Internal("mailbox".into()))
             .and_then(|r| {
                 r.map_err(|e| RespError::Internal(format!("{}", e)))?
@@ -145,14 +271,85 @@ impl<'a> RedisHandle<'a> {
             })
     }
 
+    /* round trip time of a `PING` */
+    pub fn ping(&self) -> impl Future<Item = Duration, Error = RespError> {
+        let sent = Instant::now();
+        self.actor
+            .send_timeout(Command(resp_array!["PING"]), Duration::from_secs(2))
+            .map_err(|_e| RespError::Internal("mailbox".into()))
+            .and_then(
+                move |r| match r.map_err(|e| RespError::Internal(format!("{}", e)))? {
+                    RespValue::SimpleString(ref pong) if pong == "PONG" => Ok(sent.elapsed()),
+                    r => Err(RespError::Internal(format!("unexpected reply {:?}", r))),
+                },
+            )
+    }
+
+    pub fn get_values(
+        &self,
+        keys: Vec<String>,
+    ) -> impl Future<Item = Vec<Option<String>>, Error = RespError> {
+        let mut cmd: Vec<RespValue> = Vec::with_capacity(keys.len() + 1);
+        cmd.push("MGET".into());
+        cmd.extend(keys.into_iter().map(|key| key.into()));
+
+        self.actor
+            .send_timeout(Command(RespValue::Array(cmd)), Duration::from_secs(2))
+            .map_err(|_e| RespError::Internal("mailbox".into()))
+            .and_then(|r| {
+                r.map_err(|e| RespError::Internal(format!("{}", e)))?
+                    .into_vec()?
+                    .into_iter()
+                    .map(|val| match val {
+                        RespValue::Nil => Ok(None),
+                        val => val.into_string().map(Some),
+                    })
+                    .collect::<Result<Vec<Option<String>>, _>>()
+            })
+    }
+
+    /* `None` until the first node write */
+    pub fn get_change_marker(&self) -> impl Future<Item = Option<ChangeMarker>, Error = RespError> {
+        self.get_values(vec![
+            CHANGE_GENERATION_KEY.to_string(),
+            CHANGE_MODIFIED_KEY.to_string(),
+        ])
+        .map(|values| match (values.first(), values.get(1)) {
+            (Some(Some(generation)), Some(Some(modified))) => Some(ChangeMarker {
+                generation: generation.parse().ok()?,
+                modified: modified.parse().ok()?,
+                window: 0,
+            }),
+            _ => None,
+        })
+    }
+
+    pub fn get_hash_fields(
+        &self,
+        key: String,
+    ) -> impl Future<Item = Vec<String>, Error = RespError> {
+        self.actor
+            .send_timeout(Command(resp_array!["HKEYS", key]), Duration::from_secs(5))
+            .map_err(|_e| RespError::Internal("mailbox".into()))
+            .and_then(|r| {
+                r.map_err(|e| RespError::Internal(format!("{}", e)))?
+                    .into_vec()?
+                    .into_iter()
+                    .map(|val| val.into_string())
+                    .collect::<Result<Vec<String>, _>>()
+            })
+    }
+
     pub fn remove_from_set(
         &self,
         set_key: String,
         key: String,
     ) -> impl Future<Item = i64, Error = RespError> {
         self.actor
-            .send(Command(resp_array!["SREM", set_key, key]))
-            .timeout(Duration::from_secs(2))
+            .send_timeout(
+                Command(resp_array!["SREM", set_key, key]),
+                Duration::from_secs(2),
+            )
             .map_err(|_e| RespError::Internal("mailbox".into()))
             .and_then(|r| {
                 r.map_err(|e| RespError::Internal(format!("{}", e)))?
@@ -248,7 +445,7 @@ mod tests {
     fn test_scan_set() {
         let mut sys = System::new("test");
 
-        let actor = RedisActor::start("127.0.0.1:6379");
+        let actor = Redis::connect("127.0.0.1:6379");
 
         eprintln!("starting");
         let _ = sys.run_until_complete(futures::future::lazy(|| {
@@ -282,5 +479,4 @@ mod tests {
                 .map_err(|_| ())
         }));
     }
-
 }
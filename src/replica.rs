@@ -0,0 +1,419 @@
+use actix::prelude::*;
+use actix_redis::RespValue;
+use futures::future;
+use futures::prelude::*;
+use redis_tools::{AsRedisHandle, Redis};
+use schemars::JsonSchema;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::time::{Duration, Instant};
+use updater::{coalesce, write_commands, UpdateRedis};
+
+/* how often a failing replica is checked */
+const RETRY_SECS: u64 = 5;
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1e6
+}
+
+/// Another redis every write of the primary is copied to, e.g. for analytics or disaster recovery.
+/// Failed writes are retried, in order and coalesced per key, once the replica answers again.
+/// One per replica, shared by every updater.
+pub struct Replica {
+    address: String,
+    redis: Redis,
+    /// keys waiting for a retry before the oldest is dropped
+    capacity: usize,
+    in_flight: usize,
+    /// order of the writes fanned out so far
+    seq: u64,
+    /// keys of writes waiting for the replica to come back, oldest first
+    retry: VecDeque<String>,
+    waiting: HashMap<String, Waiting>,
+    retrying: bool,
+    written: u64,
+    failed: u64,
+    dropped: u64,
+    last_lag: Option<Duration>,
+}
+
+/* every write to a key not retried yet, merged into one */
+struct Waiting {
+    /// when the oldest of them was fanned out
+    queued: Instant,
+    /// the newest of them
+    seq: u64,
+    msg: UpdateRedis,
+}
+
+impl Replica {
+    pub fn start(address: String, capacity: usize) -> Addr<Syn, Replica> {
+        let redis = Redis::connect(&address);
+        Replica {
+            address,
+            redis,
+            capacity,
+            in_flight: 0,
+            seq: 0,
+            retry: VecDeque::new(),
+            waiting: HashMap::new(),
+            retrying: false,
+            written: 0,
+            failed: 0,
+            dropped: 0,
+            last_lag: None,
+        }
+        .start()
+    }
+
+    fn write(&mut self, queued: Instant, seq: u64, msg: UpdateRedis, ctx: &mut Context<Self>) {
+        self.in_flight += 1;
+        let writes: Vec<_> = write_commands(msg.clone())
+            .into_iter()
+            .map(|cmd| self.redis.send(cmd))
+            .collect();
+
+        let f = future::join_all(writes)
+            .map_err(|e| e.to_string())
+            .and_then(|replies| {
+                for reply in replies {
+                    match reply {
+                        Ok(RespValue::Error(e)) => return Err(e),
+                        Ok(_) => (),
+                        Err(e) => return Err(e.to_string()),
+                    }
+                }
+                Ok(())
+            })
+            .into_actor(self)
+            .then(move |r, act, _| {
+                act.in_flight -= 1;
+                match r {
+                    Ok(()) => {
+                        act.written += 1;
+                        act.last_lag = Some(queued.elapsed());
+                        // an earlier write to the key failed meanwhile, its retry must not
+                        // land on top of this one
+                        if !msg.is_increment() && act.waiting.contains_key(&msg.redis_key()) {
+                            act.enqueue(queued, seq, msg);
+                        }
+                    }
+                    Err(e) => {
+                        if act.retry.is_empty() {
+                            warn!("replica {} write failed: {}", act.address, e);
+                        }
+                        act.failed += 1;
+                        act.enqueue(queued, seq, msg);
+                    }
+                }
+                ::actix::fut::ok(())
+            });
+        ctx.spawn(f);
+    }
+
+    /* merged in order of `seq`, whichever write's outcome is known first */
+    fn enqueue(&mut self, queued: Instant, seq: u64, msg: UpdateRedis) {
+        let key = msg.redis_key();
+        if let Some(waiting) = self.waiting.get_mut(&key) {
+            if seq > waiting.seq {
+                coalesce(&mut waiting.msg, msg);
+                waiting.seq = seq;
+            } else {
+                let newer = mem::replace(&mut waiting.msg, msg);
+                coalesce(&mut waiting.msg, newer);
+            }
+            waiting.queued = cmp::min(waiting.queued, queued);
+            return;
+        }
+        if self.retry.len() >= self.capacity {
+            if let Some(oldest) = self.retry.pop_front() {
+                self.waiting.remove(&oldest);
+            }
+            self.dropped += 1;
+        }
+        self.retry.push_back(key.clone());
+        self.waiting.insert(key, Waiting { queued, seq, msg });
+    }
+
+    /* writes the retry queue once the replica answers */
+    fn retry(&mut self, ctx: &mut Context<Self>) {
+        if self.retry.is_empty() || self.retrying || self.in_flight > 0 {
+            return;
+        }
+        self.retrying = true;
+
+        let f = self
+            .redis
+            .as_redis_handle()
+            .ping()
+            .into_actor(self)
+            .then(|r, act, ctx| {
+                act.retrying = false;
+                match r {
+                    Ok(_) => {
+                        info!(
+                            "replica {} is back, retrying {} writes",
+                            act.address,
+                            act.retry.len()
+                        );
+                        for key in act.retry.split_off(0) {
+                            if let Some(waiting) = act.waiting.remove(&key) {
+                                act.write(waiting.queued, waiting.seq, waiting.msg, ctx);
+                            }
+                        }
+                    }
+                    Err(e) => debug!("replica {} still unavailable: {}", act.address, e),
+                }
+                ::actix::fut::ok(())
+            });
+        ctx.spawn(f);
+    }
+
+    fn retry_later(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_later(Duration::from_secs(RETRY_SECS), |act, ctx| {
+            act.retry(ctx);
+            act.retry_later(ctx);
+        });
+    }
+
+    fn status(&self) -> ReplicaStatus {
+        let lag = match self.retry.front().and_then(|key| self.waiting.get(key)) {
+            Some(waiting) => Some(waiting.queued.elapsed()),
+            None => self.last_lag,
+        };
+
+        ReplicaStatus {
+            address: self.address.clone(),
+            healthy: self.retry.is_empty(),
+            written: self.written,
+            failed: self.failed,
+            dropped: self.dropped,
+            behind: self.in_flight + self.retry.len(),
+            lag_ms: lag.map(millis),
+        }
+    }
+}
+
+impl Actor for Replica {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.retry_later(ctx);
+    }
+}
+
+/// A write of the primary, fanned out at `queued`.
+pub struct Replicate {
+    pub queued: Instant,
+    pub msg: UpdateRedis,
+}
+
+impl Message for Replicate {
+    type Result = ();
+}
+
+impl Handler<Replicate> for Replica {
+    type Result = ();
+
+    fn handle(&mut self, msg: Replicate, ctx: &mut Self::Context) {
+        self.seq += 1;
+        // keeps writes to a key in order while earlier ones wait for a retry
+        if !self.retry.is_empty() {
+            return self.enqueue(msg.queued, self.seq, msg.msg);
+        }
+        self.write(msg.queued, self.seq, msg.msg, ctx);
+    }
+}
+
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+pub struct ReplicaStatus {
+    pub address: String,
+    /// no writes waiting for a retry
+    pub healthy: bool,
+    pub written: u64,
+    pub failed: u64,
+    /// writes given up on once `capacity` keys were waiting for a retry
+    pub dropped: u64,
+    /// writes sent and not answered yet or waiting for a retry
+    pub behind: usize,
+    /// age of the oldest write waiting for a retry, or how long the last write took
+    pub lag_ms: Option<f64>,
+}
+
+pub struct Status;
+
+impl Message for Status {
+    type Result = ReplicaStatus;
+}
+
+impl Handler<Status> for Replica {
+    type Result = MessageResult<Status>;
+
+    fn handle(&mut self, _: Status, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::MemoryStore;
+    use tokio_core::reactor;
+    use updater::UpdateMap;
+
+    fn replica(capacity: usize) -> Replica {
+        Replica {
+            address: "memory".into(),
+            redis: Redis::Memory(MemoryStore::default().start()),
+            capacity,
+            in_flight: 0,
+            seq: 0,
+            retry: VecDeque::new(),
+            waiting: HashMap::new(),
+            retrying: false,
+            written: 0,
+            failed: 0,
+            dropped: 0,
+            last_lag: None,
+        }
+    }
+
+    fn node(key: &str, num_cores: &str) -> UpdateRedis {
+        let mut value = HashMap::new();
+        value.insert("num_cores".to_string(), num_cores.to_string());
+        UpdateRedis::UpdateRedisMap(UpdateMap {
+            collection: "nodeinfo".into(),
+            key: key.into(),
+            value,
+            document: None,
+        })
+    }
+
+    fn waiting_cores(replica: &Replica, key: &str) -> String {
+        match replica.waiting[&format!("nodeinfo.{}", key)].msg {
+            UpdateRedis::UpdateRedisMap(ref msg) => msg.value["num_cores"].clone(),
+            ref msg => panic!("unexpected {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn failed_writes_coalesced_per_key() {
+        let _sys = System::new("replica-test");
+        let mut replica = replica(10);
+        let earlier = Instant::now();
+
+        replica.enqueue(earlier, 1, node("0xab", "2"));
+        replica.enqueue(Instant::now(), 2, node("0xab", "4"));
+        // the outcome of an older write known last doesn't undo a newer one
+        replica.enqueue(Instant::now(), 1, node("0xcd", "8"));
+        replica.enqueue(earlier, 0, node("0xcd", "1"));
+
+        assert_eq!(replica.retry, vec!["nodeinfo.0xab", "nodeinfo.0xcd"]);
+        assert_eq!(waiting_cores(&replica, "0xab"), "4");
+        assert_eq!(waiting_cores(&replica, "0xcd"), "8");
+        assert_eq!(replica.waiting["nodeinfo.0xab"].seq, 2);
+        assert_eq!(replica.waiting["nodeinfo.0xcd"].seq, 1);
+        assert_eq!(replica.waiting["nodeinfo.0xcd"].queued, earlier);
+        assert_eq!(replica.dropped, 0);
+    }
+
+    #[test]
+    fn replayed_in_order_behind_a_failed_write() {
+        let mut sys = System::new("replica-test");
+        let mut failing = replica(10);
+        failing.seq = 1;
+        failing.failed = 1;
+        failing.enqueue(Instant::now(), 1, node("0xab", "2"));
+        let redis = failing.redis.clone();
+        let replica: Addr<Syn, _> = failing.start();
+
+        let status = sys
+            .run_until_complete(
+                replica
+                    .send(Replicate {
+                        queued: Instant::now(),
+                        msg: node("0xcd", "4"),
+                    })
+                    .and_then(|_| {
+                        replica.send(Replicate {
+                            queued: Instant::now(),
+                            msg: node("0xab", "8"),
+                        })
+                    })
+                    .and_then(|_| replica.send(Status)),
+            )
+            .unwrap();
+
+        // nothing is written until the retry
+        assert_eq!(status.written, 0);
+        assert_eq!(status.behind, 2);
+        assert!(!status.healthy);
+
+        let retry = Duration::from_secs(RETRY_SECS + 1);
+        let (status, stored) = sys
+            .run_until_complete(
+                reactor::Timeout::new(retry, Arbiter::handle())
+                    .unwrap()
+                    .map_err(|e| e.to_string())
+                    .and_then(|_| replica.send(Status).map_err(|e| e.to_string()))
+                    .and_then(move |status| {
+                        future::join_all(vec![
+                            redis.as_redis_handle().get_hash("nodeinfo.0xab".into()),
+                            redis.as_redis_handle().get_hash("nodeinfo.0xcd".into()),
+                        ])
+                        .map(|stored| (status, stored))
+                        .map_err(|e| e.to_string())
+                    }),
+            )
+            .unwrap();
+
+        assert!(status.healthy);
+        assert_eq!(status.written, 2);
+        assert_eq!(status.behind, 0);
+        // the retried write to 0xab carries the newer write queued behind it
+        assert_eq!(stored[0]["num_cores"], "8");
+        assert_eq!(stored[1]["num_cores"], "4");
+    }
+
+    #[test]
+    fn oldest_key_dropped_when_full() {
+        let _sys = System::new("replica-test");
+        let mut replica = replica(2);
+
+        replica.enqueue(Instant::now(), 1, node("0x01", "1"));
+        replica.enqueue(Instant::now(), 2, node("0x02", "1"));
+        replica.enqueue(Instant::now(), 3, node("0x02", "2"));
+        assert_eq!(replica.dropped, 0);
+
+        replica.enqueue(Instant::now(), 4, node("0x03", "1"));
+        assert_eq!(replica.dropped, 1);
+        assert_eq!(replica.retry, vec!["nodeinfo.0x02", "nodeinfo.0x03"]);
+        assert!(!replica.waiting.contains_key("nodeinfo.0x01"));
+    }
+
+    #[test]
+    fn behind_and_lag() {
+        let _sys = System::new("replica-test");
+        let mut replica = replica(10);
+
+        replica.last_lag = Some(Duration::from_millis(20));
+        let status = replica.status();
+        assert!(status.healthy);
+        assert_eq!(status.behind, 0);
+        assert_eq!(status.lag_ms, Some(20.0));
+
+        replica.in_flight = 1;
+        replica.enqueue(
+            Instant::now() - Duration::from_millis(500),
+            1,
+            node("0xab", "2"),
+        );
+        replica.enqueue(Instant::now(), 2, node("0xcd", "2"));
+        let status = replica.status();
+        assert!(!status.healthy);
+        assert_eq!(status.behind, 3);
+        // the oldest waiting write, not the last one written
+        assert!(status.lag_ms.unwrap() >= 500.0);
+    }
+}
@@ -0,0 +1,277 @@
+use actix::actors::signal::{ProcessSignals, Signal, SignalType, Subscribe};
+use actix::msgs::SystemExit;
+use actix::prelude::*;
+use actix_web::server::StopServer;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "redis")]
+use memory::{MemoryStore, Snapshot};
+#[cfg(feature = "redis")]
+use updater::{Drain, Updater};
+#[cfg(feature = "stats_update")]
+use webhook::{Flush, Webhook};
+
+/// Handles SIGTERM and SIGINT: stops accepting connections, waits for the http workers
+/// to finish their requests and then for every registered `Updater` to get its queued
+/// writes into redis and every `Webhook` to send its queued reports, all within a single
+/// `deadline`, before exiting. A persisted
+/// in-memory store is saved last, however long that takes.
+/// A second signal exits right away.
+pub struct Shutdown {
+    server: Option<Recipient<Syn, StopServer>>,
+    deadline: Duration,
+    #[cfg(feature = "redis")]
+    updaters: Vec<Addr<Syn, Updater>>,
+    #[cfg(feature = "redis")]
+    stores: Vec<Addr<Syn, MemoryStore>>,
+    #[cfg(feature = "stats_update")]
+    webhooks: Vec<Addr<Syn, Webhook>>,
+    stopping: bool,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            server: None,
+            deadline: Duration::from_secs(10),
+            #[cfg(feature = "redis")]
+            updaters: Vec::new(),
+            #[cfg(feature = "redis")]
+            stores: Vec::new(),
+            #[cfg(feature = "stats_update")]
+            webhooks: Vec::new(),
+            stopping: false,
+        }
+    }
+}
+
+impl Actor for Shutdown {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        Arbiter::system_registry()
+            .get::<ProcessSignals>()
+            .do_send(Subscribe(ctx.address::<Addr<Syn, _>>().recipient()));
+    }
+}
+
+impl Supervised for Shutdown {}
+
+impl SystemService for Shutdown {}
+
+/// Server to stop and how long to wait for it and for pending writes.
+pub struct Configure {
+    pub server: Recipient<Syn, StopServer>,
+    pub deadline: Duration,
+}
+
+impl Message for Configure {
+    type Result = ();
+}
+
+impl Handler<Configure> for Shutdown {
+    type Result = ();
+
+    fn handle(&mut self, msg: Configure, _: &mut Self::Context) {
+        self.server = Some(msg.server);
+        self.deadline = msg.deadline;
+    }
+}
+
+/// Sent by every `Updater` when it starts, its writes are drained on shutdown.
+#[cfg(feature = "redis")]
+pub struct Register(pub Addr<Syn, Updater>);
+
+#[cfg(feature = "redis")]
+impl Message for Register {
+    type Result = ();
+}
+
+#[cfg(feature = "redis")]
+impl Handler<Register> for Shutdown {
+    type Result = ();
+
+    fn handle(&mut self, msg: Register, _: &mut Self::Context) {
+        self.updaters.push(msg.0);
+    }
+}
+
+/// Sent by a `MemoryStore` once it is persisted, it is saved after the writes are drained.
+#[cfg(feature = "redis")]
+pub struct RegisterStore(pub Addr<Syn, MemoryStore>);
+
+#[cfg(feature = "redis")]
+impl Message for RegisterStore {
+    type Result = ();
+}
+
+#[cfg(feature = "redis")]
+impl Handler<RegisterStore> for Shutdown {
+    type Result = ();
+
+    fn handle(&mut self, msg: RegisterStore, _: &mut Self::Context) {
+        self.stores.push(msg.0);
+    }
+}
+
+/// Sent by every `Webhook` when it starts, its queued reports are sent on shutdown.
+#[cfg(feature = "stats_update")]
+pub struct RegisterWebhook(pub Addr<Syn, Webhook>);
+
+#[cfg(feature = "stats_update")]
+impl Message for RegisterWebhook {
+    type Result = ();
+}
+
+#[cfg(feature = "stats_update")]
+impl Handler<RegisterWebhook> for Shutdown {
+    type Result = ();
+
+    fn handle(&mut self, msg: RegisterWebhook, _: &mut Self::Context) {
+        self.webhooks.push(msg.0);
+    }
+}
+
+impl Handler<Signal> for Shutdown {
+    type Result = ();
+
+    fn handle(&mut self, msg: Signal, _: &mut Self::Context) {
+        match msg.0 {
+            SignalType::Int | SignalType::Term => (),
+            _ => return,
+        }
+        if self.stopping {
+            warn!("{:?} received again, exiting without waiting", msg.0);
+            return Arbiter::system().do_send(SystemExit(1));
+        }
+        self.stopping = true;
+        info!("{:?} received, shutting down", msg.0);
+
+        let stop_server = match self.server {
+            Some(ref server) => Either::A(server.send(StopServer { graceful: true }).then(|r| {
+                if let Err(e) = r {
+                    warn!("unable to stop the server: {}", e);
+                }
+                Ok(())
+            })),
+            None => Either::B(future::ok(())),
+        };
+        // the server is stopped within `deadline` too, see `main`
+        let until = Instant::now() + self.deadline;
+        let drain = self.drain(until).join(self.flush(until)).map(|_| ());
+        let save = self.save();
+
+        Arbiter::handle().spawn(stop_server.and_then(|_| drain).and_then(|_| save).then(
+            |_: Result<(), ()>| {
+                Arbiter::system().do_send(SystemExit(0));
+                Ok(())
+            },
+        ));
+    }
+}
+
+impl Shutdown {
+    /* lazy, so writes queued while the server stops are waited for too, in whatever
+     * time stopping it left */
+    #[cfg(feature = "redis")]
+    fn drain(&self, until: Instant) -> impl Future<Item = (), Error = ()> {
+        let updaters = self.updaters.clone();
+
+        future::lazy(move || {
+            let deadline = until.saturating_duration_since(Instant::now());
+            info!("waiting up to {:?} for pending redis writes", deadline);
+            future::join_all(
+                updaters
+                    .iter()
+                    .map(|updater| {
+                        updater.send(Drain { deadline }).then(|r| {
+                            Ok(match r {
+                                Ok(Ok(dropped)) => dropped,
+                                Ok(Err(())) | Err(_) => {
+                                    warn!("updater gone before its writes were drained");
+                                    0
+                                }
+                            })
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .map(|dropped: Vec<usize>| match dropped.iter().sum::<usize>() {
+            0 => info!("all redis writes completed"),
+            dropped => error!("{} redis writes dropped on shutdown", dropped),
+        })
+    }
+
+    #[cfg(not(feature = "redis"))]
+    fn drain(&self, _: Instant) -> impl Future<Item = (), Error = ()> {
+        future::ok(())
+    }
+
+    #[cfg(feature = "stats_update")]
+    fn flush(&self, until: Instant) -> impl Future<Item = (), Error = ()> {
+        let webhooks = self.webhooks.clone();
+
+        future::lazy(move || {
+            let deadline = until.saturating_duration_since(Instant::now());
+            future::join_all(
+                webhooks
+                    .iter()
+                    .map(|webhook| {
+                        webhook.send(Flush { deadline }).then(|r| {
+                            Ok(match r {
+                                Ok(Ok(undelivered)) => undelivered,
+                                Ok(Err(())) | Err(_) => {
+                                    warn!("webhook gone before its reports were sent");
+                                    0
+                                }
+                            })
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .map(
+            |undelivered: Vec<usize>| match undelivered.iter().sum::<usize>() {
+                0 => (),
+                undelivered => error!("{} webhook reports undelivered on shutdown", undelivered),
+            },
+        )
+    }
+
+    #[cfg(not(feature = "stats_update"))]
+    fn flush(&self, _: Instant) -> impl Future<Item = (), Error = ()> {
+        future::ok(())
+    }
+
+    #[cfg(feature = "redis")]
+    fn save(&self) -> impl Future<Item = (), Error = ()> {
+        let stores = self.stores.clone();
+
+        future::lazy(move || {
+            future::join_all(
+                stores
+                    .iter()
+                    .map(|store| {
+                        store.send(Snapshot).then(|r| {
+                            match r {
+                                Ok(Ok(())) => info!("memory store saved"),
+                                Ok(Err(e)) => error!("unable to save the memory store: {}", e),
+                                Err(e) => error!("memory store gone before it was saved: {}", e),
+                            }
+                            Ok(())
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .map(|_: Vec<()>| ())
+    }
+
+    #[cfg(not(feature = "redis"))]
+    fn save(&self) -> impl Future<Item = (), Error = ()> {
+        future::ok(())
+    }
+}
@@ -0,0 +1,345 @@
+use actix::prelude::*;
+use serde_json;
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use updater::{coalesce, UpdateRedis};
+
+/* the log is compacted once it holds this many entries, or twice as many as after the last compaction */
+const COMPACT_AFTER: usize = 10000;
+
+/// Append-only NDJSON log of writes that didn't make it to redis, one `UpdateRedis` per line.
+/// Entries left by a previous run are picked up on start.
+/// Shared by every updater: while it holds entries, or they are being replayed by one of
+/// them, all writes are appended to it so they reach redis in order.
+pub struct Spill {
+    path: PathBuf,
+    file: Option<File>,
+    entries: usize,
+    compact_after: usize,
+    spilling: Arc<AtomicBool>,
+    /// entries were taken and not all of them reported written yet
+    replaying: bool,
+}
+
+/// Address of the `Spill` actor along with its state, cloned into every updater.
+#[derive(Clone)]
+pub struct SpillLog {
+    pub addr: Addr<Syn, Spill>,
+    spilling: Arc<AtomicBool>,
+}
+
+impl SpillLog {
+    pub fn start(path: PathBuf) -> Self {
+        let spilling = Arc::new(AtomicBool::new(false));
+        let flag = spilling.clone();
+        let addr = SyncArbiter::start(1, move || Spill::new(path.clone(), flag.clone()));
+
+        SpillLog { addr, spilling }
+    }
+
+    /// New writes are to be appended rather than sent to redis.
+    pub fn is_spilling(&self) -> bool {
+        self.spilling.load(Ordering::SeqCst)
+    }
+}
+
+impl Spill {
+    pub fn new(path: PathBuf, spilling: Arc<AtomicBool>) -> Self {
+        let entries = match read_log(&path) {
+            Ok(entries) => entries.len(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => {
+                error!("unable to read {}: {}", path.display(), e);
+                0
+            }
+        };
+        if entries > 0 {
+            info!("{} spilled writes left in {}", entries, path.display());
+            spilling.store(true, Ordering::SeqCst);
+        }
+
+        Spill {
+            path,
+            file: None,
+            entries,
+            compact_after: COMPACT_AFTER,
+            spilling,
+            replaying: false,
+        }
+    }
+
+    fn append(&mut self, msg: &UpdateRedis) -> io::Result<()> {
+        self.spilling.store(true, Ordering::SeqCst);
+        if self.file.is_none() {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            self.file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+
+        let mut line = serde_json::to_vec(msg)?;
+        line.push(b'\n');
+        self.file.as_mut().unwrap().write_all(&line)?;
+        self.entries += 1;
+
+        if self.entries >= self.compact_after {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /* replaces the log with `entries` */
+    fn rewrite(&mut self, entries: &[UpdateRedis]) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("compacting");
+        {
+            let mut file = File::create(&tmp)?;
+            for entry in entries {
+                serde_json::to_writer(&mut file, entry)?;
+                file.write_all(b"\n")?;
+            }
+            file.sync_all()?;
+        }
+        self.file = None;
+        fs::rename(&tmp, &self.path)?;
+        self.entries = entries.len();
+        Ok(())
+    }
+
+    /* rewrites the log with the newest state per key only */
+    fn compact(&mut self) -> io::Result<()> {
+        let entries = compacted(read_log(&self.path)?);
+        let before = self.entries;
+        self.rewrite(&entries)?;
+
+        info!(
+            "spill log compacted from {} to {} entries",
+            before,
+            entries.len()
+        );
+        self.compact_after = cmp::max(COMPACT_AFTER, entries.len() * 2);
+        Ok(())
+    }
+
+    /* `None` while a replay is in progress already */
+    fn take(&mut self) -> io::Result<Option<Vec<UpdateRedis>>> {
+        if self.replaying {
+            return Ok(None);
+        }
+        if self.entries == 0 {
+            self.spilling.store(false, Ordering::SeqCst);
+            return Ok(Some(Vec::new()));
+        }
+        let entries = compacted(read_log(&self.path)?);
+
+        self.file = None;
+        fs::remove_file(&self.path)?;
+        self.entries = 0;
+        self.compact_after = COMPACT_AFTER;
+        self.replaying = true;
+        Ok(Some(entries))
+    }
+
+    /* entries not written go back in front of the ones appended meanwhile, which are newer */
+    fn replayed(&mut self, unwritten: Vec<UpdateRedis>) -> io::Result<()> {
+        self.replaying = false;
+        if unwritten.is_empty() {
+            return Ok(());
+        }
+        let mut entries = unwritten;
+        if self.entries > 0 {
+            entries.extend(read_log(&self.path)?);
+        }
+        self.rewrite(&compacted(entries))
+    }
+}
+
+impl Actor for Spill {
+    type Context = SyncContext<Self>;
+}
+
+/// Appends a write that failed, or arrived while spilling. Answered once it is in the log.
+pub struct Append(pub UpdateRedis);
+
+impl Message for Append {
+    type Result = Result<(), io::Error>;
+}
+
+impl Handler<Append> for Spill {
+    type Result = Result<(), io::Error>;
+
+    fn handle(&mut self, msg: Append, _: &mut Self::Context) -> Self::Result {
+        self.append(&msg.0).map_err(|e| {
+            error!(
+                "write to {} dropped, unable to spill: {}",
+                msg.0.redis_key(),
+                e
+            );
+            // reopen on the next append
+            self.file = None;
+            e
+        })
+    }
+}
+
+/// Empties the log, answering its entries compacted, oldest first, to be replayed by the
+/// sender, which answers with `Replayed`. Answered with `None` while another replay is in
+/// progress. Once the log is empty, writes go to redis again.
+pub struct Take;
+
+impl Message for Take {
+    type Result = Result<Option<Vec<UpdateRedis>>, io::Error>;
+}
+
+impl Handler<Take> for Spill {
+    type Result = Result<Option<Vec<UpdateRedis>>, io::Error>;
+
+    fn handle(&mut self, _: Take, _: &mut Self::Context) -> Self::Result {
+        self.take()
+    }
+}
+
+/// Ends a replay with the taken entries that didn't make it to redis.
+pub struct Replayed(pub Vec<UpdateRedis>);
+
+impl Message for Replayed {
+    type Result = Result<(), io::Error>;
+}
+
+impl Handler<Replayed> for Spill {
+    type Result = Result<(), io::Error>;
+
+    fn handle(&mut self, msg: Replayed, _: &mut Self::Context) -> Self::Result {
+        let unwritten = msg.0.len();
+        self.replayed(msg.0).map_err(|e| {
+            error!(
+                "{} spilled writes dropped, unable to requeue: {}",
+                unwritten, e
+            );
+            e
+        })
+    }
+}
+
+/* stops at the first unreadable line, e.g. one cut short by a crash */
+fn read_log(path: &Path) -> io::Result<Vec<UpdateRedis>> {
+    let mut entries = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        match serde_json::from_str(&line?) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                warn!("{}: spill log truncated ({})", path.display(), e);
+                break;
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/* one entry per key, in order of the key's first write, later writes merged into it */
+fn compacted(entries: Vec<UpdateRedis>) -> Vec<UpdateRedis> {
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    let mut compacted: Vec<UpdateRedis> = Vec::new();
+
+    for entry in entries {
+        let key = entry.redis_key();
+        match positions.get(&key) {
+            Some(&position) => coalesce(&mut compacted[position], entry),
+            None => {
+                positions.insert(key, compacted.len());
+                compacted.push(entry);
+            }
+        }
+    }
+    compacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use updater::UpdateVal;
+
+    fn p2p(key: &str, value: &str) -> UpdateRedis {
+        UpdateRedis::UpdateRedisVal(UpdateVal {
+            collection: "p2pstats".into(),
+            key: key.into(),
+            value: value.into(),
+            ttl: None,
+        })
+    }
+
+    fn values(entries: Vec<UpdateRedis>) -> Vec<(String, String)> {
+        entries
+            .into_iter()
+            .map(|entry| match entry {
+                UpdateRedis::UpdateRedisVal(u) => (u.key, u.value),
+                e => panic!("unexpected {:?}", e),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn append_compact_take() {
+        let path = env::temp_dir()
+            .join(format!("golem-monitor-spill-{}", ::std::process::id()))
+            .join("spill.ndjson");
+        let mut spill = Spill::new(path.clone(), Arc::new(AtomicBool::new(false)));
+        spill.compact_after = 3;
+
+        spill.append(&p2p("n1", "1")).unwrap();
+        spill.append(&p2p("n2", "1")).unwrap();
+        spill.append(&p2p("n1", "2")).unwrap();
+        // compacted on the third entry
+        assert_eq!(spill.entries, 2);
+        spill.append(&p2p("n3", "1")).unwrap();
+
+        // left for the next run
+        let spilling = Arc::new(AtomicBool::new(false));
+        let mut spill = Spill::new(path.clone(), spilling.clone());
+        assert_eq!(spill.entries, 3);
+        assert!(spilling.load(Ordering::SeqCst));
+
+        let taken = spill.take().unwrap().unwrap();
+        assert_eq!(
+            values(taken.clone()),
+            vec![
+                ("n1".to_string(), "2".to_string()),
+                ("n2".to_string(), "1".to_string()),
+                ("n3".to_string(), "1".to_string()),
+            ]
+        );
+        assert!(!path.exists());
+        // one replay at a time
+        assert!(spill.take().unwrap().is_none());
+
+        // n1 and n2 failed again, n1 changed meanwhile
+        spill.append(&p2p("n1", "3")).unwrap();
+        spill.replayed(taken[..2].to_vec()).unwrap();
+        assert_eq!(
+            values(spill.take().unwrap().unwrap()),
+            vec![
+                ("n1".to_string(), "3".to_string()),
+                ("n2".to_string(), "1".to_string()),
+            ]
+        );
+        spill.replayed(Vec::new()).unwrap();
+        assert!(spilling.load(Ordering::SeqCst));
+        assert!(spill.take().unwrap().unwrap().is_empty());
+        assert!(!spilling.load(Ordering::SeqCst));
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
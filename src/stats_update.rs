@@ -1,10 +1,19 @@
 use super::get_client_ip;
 use actix::prelude::*;
-use actix_redis::RedisActor;
 use actix_web::dev::Handler;
 use actix_web::{self, AsyncResponder, HttpMessage, HttpRequest, HttpResponse};
+use archive::{ArchiveRecord, Archiver};
+use bytes::Bytes;
+use counters::{self, CounterGroup, Track, Tracker};
+use events::{EventHub, EventKind, NodeEvent};
 use futures::future;
 use futures::future::Future;
+use futures::stream::{self, Stream};
+use openapi::{self, ApiDoc};
+use redis_tools::{encode_hash_field, Redis};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{Schema, SchemaObject};
+use schemars::JsonSchema;
 use serde;
 use serde::de;
 use serde::de::MapAccess;
@@ -18,14 +27,97 @@ use std::net::IpAddr;
 use std::str::FromStr;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
-use updater::{UpdateMap, UpdateRedis, UpdateVal, Updater};
+use updater::{self, UpdateIncr, UpdateMap, UpdateRedis, UpdateVal, Updater, UpdaterConfig};
+use webhook::{Report, Webhooks};
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, JsonSchema, Debug)]
 struct Envelope<T> {
     proto_ver: u64,
     data: T,
 }
 
+/// Highest `proto_ver` decoded. Reports of newer clients are rejected rather than misread.
+const MAX_PROTO_VER: u64 = 2;
+
+/// Requests received per `proto_ver`, rejected ones included, in the `stats.proto_ver` hash.
+static PROTO_VER_COUNTS_COLLECTION: &str = "stats";
+static PROTO_VER_COUNTS_KEY: &str = "proto_ver";
+
+/// Field of `PROTO_VER_COUNTS_KEY` counting requests without an understood `proto_ver`.
+static OTHER_PROTO_VER: &str = "other";
+
+#[derive(Deserialize, Debug)]
+struct RawEnvelope {
+    proto_ver: u64,
+    data: Value,
+}
+
+#[derive(Debug)]
+enum DecodeError {
+    Json(serde_json::Error),
+    UnsupportedVersion(u64),
+}
+
+impl From<serde_json::Error> for DecodeError {
+    fn from(err: serde_json::Error) -> Self {
+        DecodeError::Json(err)
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Json(e) => write!(f, "{}", e),
+            DecodeError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported proto_ver {}, at most {} is understood",
+                v, MAX_PROTO_VER
+            ),
+        }
+    }
+}
+
+/* each `proto_ver` is decoded by its own rules */
+fn decode_envelope(envelope: Value) -> Result<Envelope<GolemRequest>, DecodeError> {
+    let RawEnvelope { proto_ver, data } = serde_json::from_value(envelope)?;
+    let data = match proto_ver {
+        0 => decode_v0(data)?,
+        1..=MAX_PROTO_VER => serde_json::from_value(data)?,
+        _ => return Err(DecodeError::UnsupportedVersion(proto_ver)),
+    };
+    Ok(Envelope { proto_ver, data })
+}
+
+/* the oldest clients report their platform as `metadata.os` instead of `os_info` */
+fn decode_v0(mut data: Value) -> Result<GolemRequest, serde_json::Error> {
+    if let Some(metadata) = data.get_mut("metadata").and_then(Value::as_object_mut) {
+        if !metadata.contains_key("os_info") {
+            if let Some(os) = metadata.remove("os") {
+                metadata.insert("os_info".into(), json!({ "platform": os }));
+            }
+        }
+    }
+    serde_json::from_value(data)
+}
+
+/* clients can't grow the hash with made-up versions */
+fn proto_ver_field(envelope: &Value) -> String {
+    match envelope.get("proto_ver").and_then(Value::as_u64) {
+        Some(v) if v <= MAX_PROTO_VER => v.to_string(),
+        _ => OTHER_PROTO_VER.to_string(),
+    }
+}
+
+fn count_proto_ver(updater: &Addr<Unsync, Updater>, envelope: &Value) {
+    let mut value = HashMap::new();
+    value.insert(proto_ver_field(envelope), 1);
+    updater.do_send(UpdateRedis::UpdateRedisIncr(UpdateIncr {
+        collection: PROTO_VER_COUNTS_COLLECTION.into(),
+        key: PROTO_VER_COUNTS_KEY.into(),
+        value,
+    }));
+}
+
 #[derive(Deserialize, Debug)]
 struct ObjectEnvelope<T> {
     #[serde(rename = "type")]
@@ -33,16 +125,16 @@ struct ObjectEnvelope<T> {
     obj: T,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, JsonSchema, Debug)]
 struct GolemRequest {
     cliid: String,
-    timestamp: f64, // ignored, system time will be used
+    timestamp: f64, // used for clock skew only, system time is stored as timestamp
 
     #[serde(flatten)]
     body: GolemRequestBody,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
 #[serde(tag = "type")]
 enum GolemRequestBody {
     Login {
@@ -66,6 +158,7 @@ enum GolemRequestBody {
         extra: HashMap<String, Value>,
     },
     Stats {
+        sessid: Option<String>,
         #[serde(default)]
         known_tasks: u64,
         #[serde(default)]
@@ -90,6 +183,7 @@ enum GolemRequestBody {
         extra: HashMap<String, Value>,
     },
     RequestorStats {
+        sessid: Option<String>,
         #[serde(default)]
         tasks_cnt: u64,
         #[serde(default)]
@@ -122,6 +216,7 @@ enum GolemRequestBody {
         failed_total_time: f64,
     },
     RequestorAggregateStats {
+        sessid: Option<String>,
         #[serde(default)]
         requestor_payment_cnt: u64,
         #[serde(default)]
@@ -138,6 +233,7 @@ enum GolemRequestBody {
         requestor_velocity_comp_time: u64,
     },
     ProviderStats {
+        sessid: Option<String>,
         #[serde(default)]
         provider_wtct_cnt: u64,
         #[serde(default)]
@@ -167,39 +263,198 @@ enum GolemRequestBody {
     },
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+impl GolemRequestBody {
+    /* the `type` tag */
+    fn type_name(&self) -> &'static str {
+        match self {
+            GolemRequestBody::Login { .. } => "Login",
+            GolemRequestBody::Logout { .. } => "Logout",
+            GolemRequestBody::Stats { .. } => "Stats",
+            GolemRequestBody::VMSnapshot { .. } => "VMSnapshot",
+            GolemRequestBody::P2PSnapshot { .. } => "P2PSnapshot",
+            GolemRequestBody::RequestorStats { .. } => "RequestorStats",
+            GolemRequestBody::RequestorAggregateStats { .. } => "RequestorAggregateStats",
+            GolemRequestBody::ProviderStats { .. } => "ProviderStats",
+            GolemRequestBody::TaskComputer { .. } => "TaskComputer",
+            GolemRequestBody::NodeInfo { .. } => "NodeInfo",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
 struct Metadata {
     net: Option<String>,
     version: Option<String>,
     #[serde(deserialize_with = "string_or_struct")]
+    #[schemars(schema_with = "string_or_struct_schema::<Settings>")]
     settings: Settings,
     #[serde(deserialize_with = "string_or_struct")]
     #[serde(default)]
+    #[schemars(schema_with = "string_or_struct_schema::<OSInfo>")]
     os_info: OSInfo,
-    os: Option<String>, // Backwards-compatibility
 
     #[serde(flatten)]
     extra: HashMap<String, Value>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
 struct NVGPU {
     is_supported: bool,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+/// `ClientConfigDescriptor` as sent by the client. Older and newer clients differ in
+/// both the set of fields and their types (flags are `0`/`1`, numbers are sometimes
+/// strings), so every field is optional and values that don't fit are dropped.
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
 struct Settings {
+    #[serde(default, deserialize_with = "lenient")]
+    node_name: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    hardware_preset_name: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    node_address: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    public_address: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    eth_account: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    seed_host: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<u16>")]
+    seed_port: Option<u16>,
+    #[serde(default, deserialize_with = "lenient")]
+    seeds: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<u16>")]
     start_port: Option<u16>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<u16>")]
     end_port: Option<u16>,
-    estimated_blender_performance: Option<String>,
-    estimated_lux_performance: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    rpc_address: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<u16>")]
+    rpc_port: Option<u16>,
+    #[serde(default, deserialize_with = "lenient")]
+    manager_address: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<u16>")]
+    manager_port: Option<u16>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<u16>")]
+    plugin_port: Option<u16>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<u32>")]
+    opt_peer_num: Option<u32>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<u32>")]
+    key_difficulty: Option<u32>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    #[schemars(schema_with = "flag_schema")]
+    send_pings: Option<bool>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    pings_interval: Option<f64>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    #[schemars(schema_with = "flag_schema")]
+    use_ipv6: Option<bool>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    #[schemars(schema_with = "flag_schema")]
+    use_upnp: Option<bool>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    #[schemars(schema_with = "flag_schema")]
+    enable_talkback: Option<bool>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    #[schemars(schema_with = "flag_schema")]
+    enable_monitor: Option<bool>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    #[schemars(schema_with = "flag_schema")]
+    debug_third_party: Option<bool>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    #[schemars(schema_with = "flag_schema")]
+    accept_tasks: Option<bool>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    #[schemars(schema_with = "flag_schema")]
+    add_tasks: Option<bool>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    getting_peers_interval: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    getting_tasks_interval: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    task_request_interval: Option<f64>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    #[schemars(schema_with = "flag_schema")]
+    use_waiting_for_task_timeout: Option<bool>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    waiting_for_task_timeout: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    waiting_for_task_session_timeout: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    forwarded_session_request_timeout: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    p2p_session_timeout: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    task_session_timeout: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    resource_session_timeout: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    node_snapshot_interval: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    network_check_interval: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    max_results_sending_delay: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    clean_resources_older_than_seconds: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    clean_tasks_older_than_seconds: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
     estimated_performance: Option<f64>,
-    max_memory_size: Option<f64>,
-    max_price: Option<u64>,
+    #[serde(default, deserialize_with = "lenient")]
+    estimated_lux_performance: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    estimated_blender_performance: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<u64>")]
     min_price: Option<u64>,
-    max_resource_size: Option<f64>,
-    node_name: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<u64>")]
+    max_price: Option<u64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    computing_trust: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    requesting_trust: Option<f64>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    #[schemars(schema_with = "flag_schema")]
+    use_distributed_resource_management: Option<bool>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<u32>")]
+    dist_res_num: Option<u32>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<u32>")]
     num_cores: Option<u32>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    max_resource_size: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    #[schemars(schema_with = "lenient_schema::<f64>")]
+    max_memory_size: Option<f64>,
 
     #[serde(flatten)]
     extra: HashMap<String, Value>,
@@ -214,7 +469,7 @@ impl FromStr for Settings {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Default)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default)]
 struct OSInfo {
     platform: Option<String>,
     system: Option<String>,
@@ -284,7 +539,71 @@ where
     deserializer.deserialize_any(StringOrStruct(PhantomData))
 }
 
-#[derive(Serialize, Debug)]
+/* the object itself or a JSON string holding `{"type": ..., "obj": {...}}` */
+fn string_or_struct_schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = SchemaObject::default();
+    schema.subschemas().any_of = Some(vec![
+        gen.subschema_for::<String>(),
+        gen.subschema_for::<T>(),
+    ]);
+    schema.into()
+}
+
+/* numbers may come as strings, anything that doesn't parse is treated as missing */
+fn lenient<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: de::DeserializeOwned + FromStr,
+    D: Deserializer<'de>,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(s) => s.trim().parse().ok(),
+        value => serde_json::from_value(value).ok(),
+    })
+}
+
+fn lenient_schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = SchemaObject::default();
+    schema.subschemas().any_of = Some(vec![
+        gen.subschema_for::<T>(),
+        gen.subschema_for::<String>(),
+    ]);
+    schema
+        .extensions
+        .insert("nullable".into(), Value::Bool(true));
+    schema.into()
+}
+
+/* flags are `0`/`1` in the client config, but `true`, `"1"` or `"yes"` are seen too */
+fn lenient_bool<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Bool(b) => Some(b),
+        Value::Number(n) => n.as_f64().map(|n| n != 0.0),
+        Value::String(s) => match s.trim().to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Some(true),
+            "0" | "false" | "no" | "off" => Some(false),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+fn flag_schema(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = SchemaObject::default();
+    schema.subschemas().any_of = Some(vec![
+        gen.subschema_for::<bool>(),
+        gen.subschema_for::<u8>(),
+        gen.subschema_for::<String>(),
+    ]);
+    schema
+        .extensions
+        .insert("nullable".into(), Value::Bool(true));
+    schema.into()
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
 struct NodeInfoOutput {
     cliid: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -292,6 +611,10 @@ struct NodeInfoOutput {
     #[serde(skip_serializing_if = "Option::is_none")]
     ip: Option<IpAddr>,
     timestamp: u64,
+    /// reported time minus server time in seconds, positive when node clock is ahead
+    clock_skew: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock_skewed: Option<bool>,
     #[serde(flatten)]
     metadata: MetadataOutput,
     #[serde(flatten)]
@@ -305,10 +628,12 @@ struct NodeInfoOutput {
     #[serde(flatten)]
     provider_stats: ProviderStatsOutput,
     #[serde(flatten)]
+    derived: DerivedOutput,
+    #[serde(flatten)]
     extra: HashMap<String, Value>,
 }
 
-#[derive(Serialize, Debug, Default)]
+#[derive(Serialize, JsonSchema, Debug, Default)]
 struct MetadataOutput {
     #[serde(skip_serializing_if = "Option::is_none")]
     net: Option<String>,
@@ -337,6 +662,18 @@ struct MetadataOutput {
     #[serde(skip_serializing_if = "Option::is_none")]
     num_cores: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    hardware_preset_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    accept_tasks: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    use_upnp: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    use_ipv6: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    enable_talkback: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    opt_peer_num: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     os: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     os_system: Option<String>,
@@ -350,7 +687,7 @@ struct MetadataOutput {
     os_linux_distribution: Option<String>,
 }
 
-#[derive(Serialize, Debug, Default)]
+#[derive(Serialize, JsonSchema, Debug, Default)]
 struct StatsOutput {
     #[serde(skip_serializing_if = "Option::is_none")]
     known_tasks: Option<u64>,
@@ -366,7 +703,7 @@ struct StatsOutput {
     completed: Option<u64>,
 }
 
-#[derive(Serialize, Debug, Default)]
+#[derive(Serialize, JsonSchema, Debug, Default)]
 struct RequestorStatsOutput {
     #[serde(skip_serializing_if = "Option::is_none")]
     rs_tasks_cnt: Option<u64>,
@@ -400,13 +737,13 @@ struct RequestorStatsOutput {
     rs_failed_total_time: Option<f64>,
 }
 
-#[derive(Serialize, Debug, Default)]
+#[derive(Serialize, JsonSchema, Debug, Default)]
 struct NVGPUOutput {
     #[serde(skip_serializing_if = "Option::is_none")]
     nvgpu_is_supported: Option<bool>,
 }
 
-#[derive(Serialize, Debug, Default)]
+#[derive(Serialize, JsonSchema, Debug, Default)]
 struct RequestorAggregateStatsOutput {
     #[serde(skip_serializing_if = "Option::is_none")]
     requestor_payment_cnt: Option<u64>,
@@ -424,7 +761,7 @@ struct RequestorAggregateStatsOutput {
     requestor_velocity_comp_time: Option<u64>,
 }
 
-#[derive(Serialize, Debug, Default)]
+#[derive(Serialize, JsonSchema, Debug, Default)]
 struct ProviderStatsOutput {
     #[serde(skip_serializing_if = "Option::is_none")]
     provider_wtct_cnt: Option<u64>,
@@ -446,6 +783,32 @@ struct ProviderStatsOutput {
     provider_income_paid_sum: Option<u64>,
 }
 
+/// Computed from the raw counters of the same report, stored next to them.
+#[derive(Serialize, JsonSchema, Debug, Default)]
+struct DerivedOutput {
+    /// completed / (completed + tasks_with_errors + tasks_with_timeout)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subtasks_success_ratio: Option<f64>,
+    /// rs_finished_ok_total_time / rs_finished_ok_cnt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rs_finished_ok_avg_time: Option<f64>,
+    /// provider_wtct_to_ttc_delay_sum / provider_wtct_to_ttc_cnt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provider_wtct_to_ttc_delay_avg: Option<f64>,
+    /// provider_income_paid_sum / provider_income_completed_sum
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provider_income_paid_ratio: Option<f64>,
+}
+
+/* `None` rather than NaN or infinity while nothing has been counted yet */
+fn ratio(numerator: f64, denominator: f64) -> Option<f64> {
+    if denominator > 0.0 {
+        Some(numerator / denominator)
+    } else {
+        None
+    }
+}
+
 fn protocol_versions_to_map(protocol_versions: &HashMap<String, Value>) -> HashMap<String, Value> {
     protocol_versions
         .iter()
@@ -453,6 +816,49 @@ fn protocol_versions_to_map(protocol_versions: &HashMap<String, Value>) -> HashM
         .collect()
 }
 
+impl NodeInfoOutput {
+    fn flag_clock_skew(&mut self, threshold: f64) {
+        self.clock_skewed = Some(self.clock_skew.abs() > threshold);
+    }
+
+    fn derive_metrics(&mut self) {
+        let stats = &self.stats;
+        let requestor = &self.requestor_stats;
+        let provider = &self.provider_stats;
+
+        self.derived = DerivedOutput {
+            subtasks_success_ratio: stats.completed.and_then(|completed| {
+                // summed as f64, counters near u64::MAX must not overflow the denominator
+                let finished = completed as f64
+                    + stats.tasks_with_errors.unwrap_or(0) as f64
+                    + stats.tasks_with_timeout.unwrap_or(0) as f64;
+                ratio(completed as f64, finished)
+            }),
+            rs_finished_ok_avg_time: match (
+                requestor.rs_finished_ok_total_time,
+                requestor.rs_finished_ok_cnt,
+            ) {
+                (Some(total), Some(cnt)) => ratio(total, cnt as f64),
+                _ => None,
+            },
+            provider_wtct_to_ttc_delay_avg: match (
+                provider.provider_wtct_to_ttc_delay_sum,
+                provider.provider_wtct_to_ttc_cnt,
+            ) {
+                (Some(sum), Some(cnt)) => ratio(sum as f64, cnt as f64),
+                _ => None,
+            },
+            provider_income_paid_ratio: match (
+                provider.provider_income_paid_sum,
+                provider.provider_income_completed_sum,
+            ) {
+                (Some(paid), Some(completed)) => ratio(paid as f64, completed as f64),
+                _ => None,
+            },
+        };
+    }
+}
+
 fn now_in_millis() -> u64 {
     let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
     let secs = now.as_secs() as u64;
@@ -461,18 +867,29 @@ fn now_in_millis() -> u64 {
     secs * 1000 + millis
 }
 
-// The signature of this function should be
-// fn to_node_info(cliid: String, body: GolemRequestBody, ip: Option<IpAddr>) -> Option<NodeInfoOutput>
-// but, tests are written in a way that makes refactoring this difficult
-// TODO refactor this after tests will be executed differently
+/* fixtures received now, requests and replays pass their own receive time */
+#[cfg(test)]
 fn to_node_info(envelope: Envelope<GolemRequest>, ip: Option<IpAddr>) -> Option<NodeInfoOutput> {
-    let GolemRequest { cliid, body, .. } = envelope.data;
+    to_node_info_at(envelope, ip, now_in_millis())
+}
 
-    let timestamp = now_in_millis();
+/* `timestamp` is the receive time, replayed requests keep their original one */
+fn to_node_info_at(
+    envelope: Envelope<GolemRequest>,
+    ip: Option<IpAddr>,
+    timestamp: u64,
+) -> Option<NodeInfoOutput> {
+    let GolemRequest {
+        cliid,
+        body,
+        timestamp: reported_timestamp,
+    } = envelope.data;
+
+    let clock_skew = reported_timestamp - timestamp as f64 / 1000.0;
 
     debug!("req type: {:?}", body);
 
-    match body {
+    let node_info = match body {
         GolemRequestBody::Login {
             metadata,
             protocol_versions,
@@ -484,6 +901,9 @@ fn to_node_info(envelope: Envelope<GolemRequest>, ip: Option<IpAddr>) -> Option<
             sessid,
             ip,
             timestamp,
+            clock_skew,
+            clock_skewed: None,
+            derived: DerivedOutput::default(),
             extra: protocol_versions_to_map(&protocol_versions),
             stats: StatsOutput::default(),
             requestor_stats: RequestorStatsOutput::default(),
@@ -510,7 +930,13 @@ fn to_node_info(envelope: Envelope<GolemRequest>, ip: Option<IpAddr>) -> Option<
                     max_resource_size: m.settings.max_resource_size.map(|f| f.trunc() as u64),
                     node_name: m.settings.node_name,
                     num_cores: m.settings.num_cores,
-                    os: m.os_info.platform.or(m.os),
+                    hardware_preset_name: m.settings.hardware_preset_name,
+                    accept_tasks: m.settings.accept_tasks,
+                    use_upnp: m.settings.use_upnp,
+                    use_ipv6: m.settings.use_ipv6,
+                    enable_talkback: m.settings.enable_talkback,
+                    opt_peer_num: m.settings.opt_peer_num,
+                    os: m.os_info.platform,
                     os_system: m.os_info.system,
                     os_release: m.os_info.release,
                     os_version: m.os_info.version,
@@ -539,11 +965,15 @@ fn to_node_info(envelope: Envelope<GolemRequest>, ip: Option<IpAddr>) -> Option<
             finished_with_failures_total_time,
             failed_cnt,
             failed_total_time,
+            ..
         } => Some(NodeInfoOutput {
             cliid,
             sessid: Option::None,
             ip,
             timestamp,
+            clock_skew,
+            clock_skewed: None,
+            derived: DerivedOutput::default(),
             metadata: MetadataOutput::default(),
             nvgpu: NVGPUOutput::default(),
             extra: HashMap::new(),
@@ -576,11 +1006,15 @@ fn to_node_info(envelope: Envelope<GolemRequest>, ip: Option<IpAddr>) -> Option<
             requestor_subtask_price_mag,
             requestor_velocity_timeout,
             requestor_velocity_comp_time,
+            ..
         } => Some(NodeInfoOutput {
             cliid,
             sessid: Option::None,
             ip,
             timestamp,
+            clock_skew,
+            clock_skewed: None,
+            derived: DerivedOutput::default(),
             metadata: MetadataOutput::default(),
             nvgpu: NVGPUOutput::default(),
             extra: HashMap::new(),
@@ -607,11 +1041,15 @@ fn to_node_info(envelope: Envelope<GolemRequest>, ip: Option<IpAddr>) -> Option<
             provider_income_assigned_sum,
             provider_income_completed_sum,
             provider_income_paid_sum,
+            ..
         } => Some(NodeInfoOutput {
             cliid,
             sessid: Option::None,
             ip,
             timestamp,
+            clock_skew,
+            clock_skewed: None,
+            derived: DerivedOutput::default(),
             metadata: MetadataOutput::default(),
             nvgpu: NVGPUOutput::default(),
             extra: HashMap::new(),
@@ -643,6 +1081,9 @@ fn to_node_info(envelope: Envelope<GolemRequest>, ip: Option<IpAddr>) -> Option<
             sessid: Option::None,
             ip,
             timestamp,
+            clock_skew,
+            clock_skewed: None,
+            derived: DerivedOutput::default(),
             metadata: MetadataOutput::default(),
             nvgpu: NVGPUOutput::default(),
             requestor_stats: RequestorStatsOutput::default(),
@@ -663,19 +1104,57 @@ fn to_node_info(envelope: Envelope<GolemRequest>, ip: Option<IpAddr>) -> Option<
             warn!("unsupported info: {:?}", v);
             None
         }
-    }
+    };
+
+    node_info.map(|mut node_info| {
+        node_info.derive_metrics();
+        node_info
+    })
+}
+
+fn node_event(request: &GolemRequest) -> NodeEvent {
+    let (kind, net) = match request.body {
+        GolemRequestBody::Login { ref metadata, .. } => (
+            EventKind::Login,
+            metadata.as_ref().and_then(|m| m.net.clone()),
+        ),
+        GolemRequestBody::Logout { .. } => (EventKind::Logout, None),
+        _ => (EventKind::Update, None),
+    };
+
+    NodeEvent::new(kind, request.cliid.clone(), net)
 }
 
 pub struct UpdateHandler {
-    updater: Addr<Unsync, Updater>,
+    ingest: Ingest,
+    archive: Option<Addr<Syn, Archiver>>,
 }
 
 impl UpdateHandler {
-    pub fn new(redis_actor: Addr<Unsync, RedisActor>) -> UpdateHandler {
+    pub fn new(
+        redis_actor: Redis,
+        updater_config: UpdaterConfig,
+        counters: Addr<Syn, Tracker>,
+        events: Addr<Syn, EventHub>,
+        archive: Option<Addr<Syn, Archiver>>,
+        webhooks: Webhooks,
+        clock_skew_threshold: f64,
+    ) -> UpdateHandler {
         UpdateHandler {
-            updater: Updater::start(redis_actor),
+            ingest: Ingest {
+                updater: Updater::start(redis_actor, updater_config),
+                counters,
+                events,
+                webhooks,
+                clock_skew_threshold,
+            },
+            archive,
         }
     }
+
+    pub fn updater(&self) -> Addr<Unsync, Updater> {
+        self.ingest.updater.clone()
+    }
 }
 
 #[derive(Debug)]
@@ -692,31 +1171,35 @@ impl From<serde_json::Error> for ConvertError {
 
 fn to_hash_map<T: serde::Serialize>(input: &T) -> Result<HashMap<String, String>, ConvertError> {
     if let serde_json::Value::Object(map) = serde_json::to_value(input)? {
-        Ok(map
-            .iter()
-            .filter_map(|(k, v)| match v {
-                serde_json::Value::String(s) => Some((k.clone(), s.clone())),
-                serde_json::Value::Number(n) => Some((k.clone(), n.to_string())),
-                serde_json::Value::Bool(b) => Some((k.clone(), format!("{}", b))),
-                _ => None,
-            })
-            .collect())
+        Ok(map.iter().map(|(k, v)| encode_hash_field(k, v)).collect())
     } else {
         Err(ConvertError::InvalidJson)
     }
 }
 
+/* `tracked` counter bookkeeping is stored along with the report */
 fn push_node_info(
     updater: &Addr<Unsync, Updater>,
     node_info: &NodeInfoOutput,
+    tracked: HashMap<String, String>,
 ) -> Box<Future<Item = HttpResponse, Error = actix_web::Error>> {
     debug!("nodeinfo {:?}", &node_info);
 
-    if let Ok(map) = to_hash_map(&node_info) {
+    if let Ok(mut map) = to_hash_map(&node_info) {
+        let mut document = serde_json::to_value(node_info).ok();
+        if let Some(Value::Object(ref mut document)) = document {
+            for (k, v) in &tracked {
+                let value = serde_json::from_str(v).unwrap_or_else(|_| Value::String(v.clone()));
+                document.insert(k.clone(), value);
+            }
+        }
+        map.extend(tracked);
+
         let msg = UpdateRedis::UpdateRedisMap(UpdateMap {
             collection: "nodeinfo".to_string(),
             key: node_info.cliid.clone(),
             value: map,
+            document,
         });
         push_msg_to_redis(updater, msg)
     } else {
@@ -736,6 +1219,7 @@ fn push_p2pstats(
         collection: "p2pstats".to_string(),
         key: cliid.to_string(),
         value: value.to_string(),
+        ttl: None,
     });
 
     push_msg_to_redis(updater, msg)
@@ -751,6 +1235,11 @@ fn push_msg_to_redis(
             .map_err(|_e| actix_web::error::ErrorInternalServerError("send error"))
             .and_then(|r| match r {
                 Ok(_v) => future::ok(HttpResponse::Ok().into()),
+                Err(updater::Error::QueueFull) => future::ok(
+                    HttpResponse::ServiceUnavailable()
+                        .header("Retry-After", updater::RETRY_AFTER_SECS.to_string())
+                        .finish(),
+                ),
                 Err(e) => future::err(actix_web::error::ErrorInternalServerError(format!(
                     "save: {}",
                     e
@@ -759,12 +1248,191 @@ fn push_msg_to_redis(
     )
 }
 
+/* cumulative counters of the report, with the session they were counted in */
+fn report_counters(body: &GolemRequestBody) -> Option<(&'static CounterGroup, Option<String>)> {
+    match body {
+        GolemRequestBody::Stats { sessid, .. } => Some((&counters::STATS, sessid.clone())),
+        GolemRequestBody::RequestorStats { sessid, .. } => {
+            Some((&counters::REQUESTOR_STATS, sessid.clone()))
+        }
+        GolemRequestBody::RequestorAggregateStats { sessid, .. } => {
+            Some((&counters::REQUESTOR_AGGREGATE_STATS, sessid.clone()))
+        }
+        GolemRequestBody::ProviderStats { sessid, .. } => {
+            Some((&counters::PROVIDER_STATS, sessid.clone()))
+        }
+        _ => None,
+    }
+}
+
+/* compares the report with the previous one of the node before overwriting it */
+fn push_counted_node_info(
+    updater: &Addr<Unsync, Updater>,
+    counters: &Addr<Syn, Tracker>,
+    node_info: NodeInfoOutput,
+    group: &'static CounterGroup,
+    sessid: Option<String>,
+    received: u64,
+) -> Box<Future<Item = HttpResponse, Error = actix_web::Error>> {
+    let updater = updater.clone();
+    let track = Track {
+        group,
+        cliid: node_info.cliid.clone(),
+        current: to_hash_map(&node_info).unwrap_or_default(),
+        sessid,
+        at: received,
+    };
+
+    Box::new(
+        counters
+            .send(track)
+            .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))
+            .and_then(|r| r.map_err(actix_web::error::ErrorInternalServerError))
+            .and_then(move |tracked| push_node_info(&updater, &node_info, tracked)),
+    )
+}
+
+/* where decoded reports go, for live requests and replayed ones alike */
+#[derive(Clone)]
+struct Ingest {
+    updater: Addr<Unsync, Updater>,
+    counters: Addr<Syn, Tracker>,
+    events: Addr<Syn, EventHub>,
+    webhooks: Webhooks,
+    clock_skew_threshold: f64,
+}
+
+impl Ingest {
+    fn report(
+        &self,
+        envelope: Envelope<GolemRequest>,
+        client_ip: Option<IpAddr>,
+        received: u64,
+    ) -> Box<Future<Item = HttpResponse, Error = actix_web::Error>> {
+        let Ingest {
+            ref updater,
+            ref counters,
+            ref events,
+            ref webhooks,
+            clock_skew_threshold,
+        } = *self;
+        let event = node_event(&envelope.data);
+        let kind = envelope.data.body.type_name();
+        let cliid = event.cliid.clone();
+        let forwarded = webhooks.wants(kind);
+        let webhooks = webhooks.clone();
+        let forward = move |data: Value| {
+            webhooks.forward(Report {
+                kind,
+                cliid: cliid.clone(),
+                received,
+                ip: client_ip,
+                data,
+            })
+        };
+        // stored reports are only forwarded once redis has them
+        let mut forwarding = None;
+
+        let stored = if let GolemRequest {
+            cliid,
+            body: GolemRequestBody::P2PSnapshot { extra },
+            ..
+        } = envelope.data
+        {
+            if forwarded {
+                forwarding = Some(serde_json::to_value(&extra).unwrap_or(Value::Null));
+            }
+            match serde_json::to_string(&extra) {
+                Ok(extra) => push_p2pstats(cliid, updater, extra),
+                Err(_e) => return Box::new(future::ok(HttpResponse::Ok().into())), // This branch will never be executed
+            }
+        } else {
+            let counted = report_counters(&envelope.data.body);
+
+            match to_node_info_at(envelope, client_ip, received) {
+                Some(mut node_info) => {
+                    node_info.flag_clock_skew(clock_skew_threshold);
+                    if forwarded {
+                        forwarding = Some(serde_json::to_value(&node_info).unwrap_or(Value::Null));
+                    }
+                    match counted {
+                        Some((group, sessid)) => push_counted_node_info(
+                            updater, counters, node_info, group, sessid, received,
+                        ),
+                        None => push_node_info(updater, &node_info, HashMap::new()),
+                    }
+                }
+                None => {
+                    // logouts are not stored, but listeners still want to know
+                    if event.kind == EventKind::Logout {
+                        if forwarded {
+                            forward(Value::Null);
+                        }
+                        events.do_send(event);
+                    }
+                    return Box::new(future::ok(HttpResponse::Ok().into()));
+                }
+            }
+        };
+
+        let events = events.clone();
+        Box::new(stored.map(move |resp| {
+            if resp.status().is_success() {
+                if let Some(data) = forwarding {
+                    forward(data);
+                }
+                events.do_send(event);
+            }
+            resp
+        }))
+    }
+}
+
+/// Runs archived requests through ingestion again, one at a time, in archive order.
+/// Resolves to the number of records stored.
+pub fn replay<I>(
+    updater: Addr<Unsync, Updater>,
+    counters: Addr<Syn, Tracker>,
+    events: Addr<Syn, EventHub>,
+    clock_skew_threshold: f64,
+    records: I,
+) -> impl Future<Item = u64, Error = actix_web::Error>
+where
+    I: Iterator<Item = ArchiveRecord>,
+{
+    // replayed reports are not forwarded again
+    let ingest = Ingest {
+        updater,
+        counters,
+        events,
+        webhooks: Webhooks::default(),
+        clock_skew_threshold,
+    };
+
+    stream::iter_ok::<_, actix_web::Error>(records)
+        .filter_map(|record| match decode_envelope(record.envelope) {
+            Ok(envelope) => Some((envelope, record.ip, record.received)),
+            Err(e) => {
+                warn!(
+                    "replay: skipping record received at {}: {}",
+                    record.received, e
+                );
+                None
+            }
+        })
+        .and_then(move |(envelope, ip, received)| ingest.report(envelope, ip, received))
+        .fold(0, |n, _| Ok::<_, actix_web::Error>(n + 1))
+}
+
 impl Handler<()> for UpdateHandler {
     type Result = Box<Future<Item = HttpResponse, Error = actix_web::Error>>;
 
     fn handle(&mut self, req: HttpRequest<()>) -> <Self as Handler<()>>::Result {
-        let updater = self.updater.clone();
+        let ingest = self.ingest.clone();
+        let counting = self.ingest.updater.clone();
+        let archive = self.archive.clone();
         let client_ip = get_client_ip(&req);
+        let received = now_in_millis();
 
         if let Some(ip) = client_ip {
             debug!("client IP {:?}", ip)
@@ -772,26 +1440,22 @@ impl Handler<()> for UpdateHandler {
             info!("no client IP")
         }
 
-        req.json()
+        req.body()
             .from_err()
-            .and_then(move |envelope: Envelope<GolemRequest>| {
-                if let GolemRequest {
-                    cliid,
-                    body: GolemRequestBody::P2PSnapshot { extra },
-                    ..
-                } = envelope.data
-                {
-                    match serde_json::to_string(&extra) {
-                        Ok(extra) => push_p2pstats(cliid, &updater, extra),
-                        Err(_e) => Box::new(future::ok(HttpResponse::Ok().into())), // This branch will never be executed
-                    }
-                } else {
-                    match to_node_info(envelope, client_ip) {
-                        Some(node_info) => push_node_info(&updater, &node_info),
-                        None => Box::new(future::ok(HttpResponse::Ok().into())),
-                    }
+            .and_then(move |body: Bytes| {
+                let envelope: Value =
+                    serde_json::from_slice(&body).map_err(actix_web::error::ErrorBadRequest)?;
+                count_proto_ver(&counting, &envelope);
+                if let Some(archive) = archive {
+                    archive.do_send(ArchiveRecord {
+                        received,
+                        ip: client_ip,
+                        envelope: envelope.clone(),
+                    });
                 }
+                decode_envelope(envelope).map_err(actix_web::error::ErrorBadRequest)
             })
+            .and_then(move |envelope| ingest.report(envelope, client_ip, received))
             .or_else(|e: actix_web::Error| {
                 let mut resp = e.as_response_error().error_response();
                 warn!("processing request, error={:?}", &e);
@@ -802,6 +1466,38 @@ impl Handler<()> for UpdateHandler {
     }
 }
 
+/// `/` and `/update` in the OpenAPI document.
+pub fn describe(doc: &mut ApiDoc) {
+    let request = doc.schema::<Envelope<GolemRequest>>();
+    // published to `PUBLISH_CHANNEL` / `PUBLISH_STREAM` for every stored report
+    doc.schema::<NodeInfoOutput>();
+
+    doc.add(
+        "/",
+        "get",
+        json!({
+            "summary": "redirects to the frontend",
+            "responses": { "301": { "description": "see `Location`" } }
+        }),
+    );
+    for path in &["/", "/update"] {
+        doc.add(
+            path,
+            "post",
+            json!({
+                "summary": "report of a Golem node",
+                "description": format!("`proto_ver` 0 to {} is understood", MAX_PROTO_VER),
+                "requestBody": openapi::json_body(request.clone()),
+                "responses": {
+                    "200": { "description": "stored, or ignored for report types that aren't stored" },
+                    "400": { "description": "not a valid report, or of an unsupported `proto_ver`" },
+                    "503": { "description": "too many writes waiting for redis, retry after `Retry-After` seconds" }
+                }
+            }),
+        );
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -821,7 +1517,10 @@ mod tests {
                 }
                 _ => panic!("login expected"),
             };
-            assert_eq!(&settings.extra["use_ipv6"], &json!(0));
+            assert_eq!(settings.use_ipv6, Some(false));
+            assert_eq!(settings.accept_tasks, Some(true));
+            assert_eq!(settings.seed_port, Some(40102));
+            assert!(settings.extra.is_empty());
         }
 
         let output = to_node_info(r, None).unwrap();
@@ -829,6 +1528,35 @@ mod tests {
         assert_eq!(output.nvgpu.nvgpu_is_supported, None);
     }
 
+    #[test]
+    fn decode_proto_versions() {
+        let legacy: Value =
+            serde_json::from_str(include_str!("../test/login-legacy.json")).unwrap();
+        let output = to_node_info(decode_envelope(legacy.clone()).unwrap(), None).unwrap();
+        assert!(output.metadata.os.is_some());
+
+        // only version 0 reports the platform as `os`
+        let mut current = legacy.clone();
+        current["proto_ver"] = json!(1);
+        let output = to_node_info(decode_envelope(current).unwrap(), None).unwrap();
+        assert!(output.metadata.os.is_none());
+
+        let v2: Value = serde_json::from_str(include_str!("../test/login-v2.json")).unwrap();
+        let output = to_node_info(decode_envelope(v2).unwrap(), None).unwrap();
+        assert_eq!(output.metadata.os, Some("linux".into()));
+
+        let mut future = legacy;
+        future["proto_ver"] = json!(MAX_PROTO_VER + 1);
+        match decode_envelope(future) {
+            Err(DecodeError::UnsupportedVersion(v)) => assert_eq!(v, MAX_PROTO_VER + 1),
+            r => panic!("unexpected {:?}", r.map(|e| e.proto_ver)),
+        }
+
+        assert_eq!(proto_ver_field(&json!({"proto_ver": 2})), "2");
+        assert_eq!(proto_ver_field(&json!({"proto_ver": 12345})), "other");
+        assert_eq!(proto_ver_field(&json!({"proto_ver": "x"})), "other");
+    }
+
     #[test]
     fn parse_login_nvgpu() {
         let input = include_str!("../test/login-nvgpu.json");
@@ -866,6 +1594,59 @@ mod tests {
         assert_eq!(output.metadata.max_memory_size, Some(12212628));
         assert_eq!(output.metadata.os, Some("linux".into()));
         assert_eq!(output.metadata.os_release, Some("4.15.0-36-generic".into()));
+        assert_eq!(output.metadata.use_upnp, Some(true));
+        assert_eq!(output.metadata.enable_talkback, Some(false));
+        assert_eq!(output.metadata.hardware_preset_name, Some("default".into()));
+
+        let map = to_hash_map(&output).unwrap();
+        assert_eq!(map["accept_tasks"], "true");
+        assert_eq!(map["opt_peer_num"], "10");
+    }
+
+    #[test]
+    fn nested_values_round_trip() {
+        use redis_tools::decode_hash;
+
+        let input = json!({
+            "cliid": "abc",
+            "num_cores": 4,
+            "protocol_versions": {"p2p": 27, "task": [1, 2]},
+            "peers": [],
+            "gpu": null
+        });
+        let map = to_hash_map(&input).unwrap();
+        assert_eq!(map["num_cores"], "4");
+        assert_eq!(map["peers:json"], "[]");
+        assert_eq!(map["gpu:json"], "null");
+
+        let decoded = decode_hash(map);
+        assert_eq!(decoded["num_cores"], json!("4"));
+        assert_eq!(decoded["protocol_versions"], input["protocol_versions"]);
+        assert_eq!(decoded["peers"], json!([]));
+        assert_eq!(decoded["gpu"], Value::Null);
+    }
+
+    #[test]
+    fn parse_settings_mistyped() {
+        let settings: Settings = serde_json::from_value(json!({
+            "start_port": "40102",
+            "end_port": "unknown",
+            "accept_tasks": "yes",
+            "use_upnp": true,
+            "use_ipv6": "maybe",
+            "opt_peer_num": 10.5,
+            "node_name": 7,
+            "some_new_field": [1, 2]
+        }))
+        .unwrap();
+        assert_eq!(settings.start_port, Some(40102));
+        assert_eq!(settings.end_port, None);
+        assert_eq!(settings.accept_tasks, Some(true));
+        assert_eq!(settings.use_upnp, Some(true));
+        assert_eq!(settings.use_ipv6, None);
+        assert_eq!(settings.opt_peer_num, None);
+        assert_eq!(settings.node_name, None);
+        assert_eq!(settings.extra["some_new_field"], json!([1, 2]));
     }
 
     #[test]
@@ -876,6 +1657,26 @@ mod tests {
         assert_eq!(output.stats.tasks_requested, Some(22518));
     }
 
+    #[test]
+    fn clock_skew() {
+        let input = include_str!("../test/stats.json");
+        let mut output = to_node_info(serde_json::from_str(input).unwrap(), None).unwrap();
+        // fixture was recorded in 2018
+        assert!(output.clock_skew < -3600.0);
+        assert_eq!(output.clock_skewed, None);
+
+        output.flag_clock_skew(60.0);
+        assert_eq!(output.clock_skewed, Some(true));
+
+        output.clock_skew = -59.5;
+        output.flag_clock_skew(60.0);
+        assert_eq!(output.clock_skewed, Some(false));
+
+        let map = to_hash_map(&output).unwrap();
+        assert_eq!(map.get("clock_skew").unwrap(), "-59.5");
+        assert_eq!(map.get("clock_skewed").unwrap(), "false");
+    }
+
     #[test]
     fn parse_requestor_stats() {
         let input = include_str!("../test/requestor-stats.json");
@@ -921,4 +1722,34 @@ mod tests {
         assert_eq!(map.get("provider_srr_cnt").unwrap(), "1");
         assert_eq!(map.get("provider_income_paid_sum").unwrap(), "100");
     }
+
+    #[test]
+    fn derived_metrics() {
+        let input = include_str!("../test/stats.json");
+        let map = to_hash_map(&to_node_info(serde_json::from_str(input).unwrap(), None)).unwrap();
+        let ratio: f64 = map.get("subtasks_success_ratio").unwrap().parse().unwrap();
+        assert!((ratio - 57.0 / 58.0).abs() < 1e-9);
+
+        // counters near u64::MAX must not overflow the denominator
+        let mut envelope: Value = serde_json::from_str(input).unwrap();
+        envelope["data"]["computed_tasks"] = json!(u64::MAX);
+        envelope["data"]["tasks_with_errors"] = json!(u64::MAX);
+        let output = to_node_info(serde_json::from_value(envelope).unwrap(), None).unwrap();
+        assert_eq!(output.derived.subtasks_success_ratio, Some(0.5));
+
+        // nothing counted yet, nothing derived
+        let input = include_str!("../test/requestor-stats.json");
+        let output = to_node_info(serde_json::from_str(input).unwrap(), None).unwrap();
+        assert_eq!(output.derived.rs_finished_ok_avg_time, None);
+
+        let mut envelope: Value =
+            serde_json::from_str(include_str!("../test/provider-stats.json")).unwrap();
+        envelope["data"]["provider_wtct_to_ttc_delay_sum"] = json!(30);
+        envelope["data"]["provider_wtct_to_ttc_cnt"] = json!(4);
+        envelope["data"]["provider_income_completed_sum"] = json!(400);
+        let output = to_node_info(serde_json::from_value(envelope).unwrap(), None).unwrap();
+        assert_eq!(output.derived.provider_wtct_to_ttc_delay_avg, Some(7.5));
+        assert_eq!(output.derived.provider_income_paid_ratio, Some(0.25));
+        assert_eq!(output.derived.subtasks_success_ratio, None);
+    }
 }
@@ -115,3 +115,53 @@ where
         max_chunk,
     }
 }
+
+struct CollectStream<Upstream, Complete> {
+    upstream: Upstream,
+    collected: Vec<u8>,
+    complete: Option<Complete>,
+}
+
+impl<Upstream, Complete> Stream for CollectStream<Upstream, Complete>
+where
+    Upstream: Stream<Item = Bytes>,
+    Complete: FnOnce(Bytes),
+{
+    type Item = Bytes;
+    type Error = Upstream::Error;
+
+    fn poll(&mut self) -> Result<Async<Option<Bytes>>, Upstream::Error> {
+        match self.upstream.poll()? {
+            Async::Ready(Some(chunk)) => {
+                if self.complete.is_some() {
+                    self.collected.extend_from_slice(&chunk);
+                }
+                Ok(Async::Ready(Some(chunk)))
+            }
+            Async::Ready(None) => {
+                if let Some(complete) = self.complete.take() {
+                    complete(Bytes::from(mem::take(&mut self.collected)));
+                }
+                Ok(Async::Ready(None))
+            }
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Passes `stream` through unchanged and hands the whole body to `complete`
+/// once it has ended without error. Without `complete` nothing is buffered.
+pub fn collect_on_complete<Upstream, Complete>(
+    stream: Upstream,
+    complete: Option<Complete>,
+) -> impl Stream<Item = Bytes, Error = Upstream::Error>
+where
+    Upstream: Stream<Item = Bytes>,
+    Complete: FnOnce(Bytes),
+{
+    CollectStream {
+        upstream: stream,
+        collected: Vec::new(),
+        complete,
+    }
+}
@@ -1,36 +1,371 @@
+use actix::fut::Either;
 use actix::prelude::*;
-use actix_redis::{Command, RedisActor, RespValue};
+use actix_redis::{Command, RespValue};
+use futures::future;
 use futures::prelude::*;
-use std::collections::HashMap;
+use futures::sync::oneshot;
+use redis_tools::{AsRedisHandle, ChangeMarker, Redis, JSON_FIELD_SUFFIX};
+use replica::{Replica, ReplicaStatus, Replicate, Status};
+use schemars::JsonSchema;
+use serde_json::{self, Value};
+use shutdown::{Register, Shutdown};
+use spill::{Append, Replayed, SpillLog, Take};
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where stored updates are additionally announced for downstream consumers.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Publish {
+    /// `PUBLISH` a json message on a pub/sub channel.
+    Channel(String),
+    /// `XADD` an entry to a stream, approximately trimmed to `maxlen` entries.
+    Stream { key: String, maxlen: Option<u64> },
+}
+
+/// What an updater does with a write while `queue_capacity` writes are already in flight.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueuePolicy {
+    /// Refuse it with `Error::QueueFull`.
+    Reject,
+    /// Keep it in a backlog of at most `queue_capacity` keys, merged into the backlogged
+    /// write to the same key, if any. Answered as soon as it is backlogged.
+    Coalesce,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(QueuePolicy::Reject),
+            "coalesce" => Ok(QueuePolicy::Coalesce),
+            _ => Err(format!(
+                "unknown queue policy {:?}, expected reject or coalesce",
+                s
+            )),
+        }
+    }
+}
+
+/// Seconds clients are asked to wait when a write is refused.
+pub const RETRY_AFTER_SECS: u64 = 5;
+
+/* how often redis is checked while writes are being spilled */
+const SPILL_RETRY_SECS: u64 = 5;
+
+#[derive(Clone)]
+pub struct UpdaterConfig {
+    pub publish: Vec<Publish>,
+    /// writes sent to redis and not answered yet before `queue_policy` kicks in
+    pub queue_capacity: usize,
+    pub queue_policy: QueuePolicy,
+    /// where failed writes go, to be replayed once redis is back
+    pub spill: Option<SpillLog>,
+    /// redis instances every write is copied to, shared by all updaters
+    pub replicas: Vec<Addr<Syn, Replica>>,
+}
+
+impl Default for UpdaterConfig {
+    fn default() -> Self {
+        UpdaterConfig {
+            publish: Vec::new(),
+            queue_capacity: 10000,
+            queue_policy: QueuePolicy::Reject,
+            spill: None,
+            replicas: Vec::new(),
+        }
+    }
+}
 
 pub struct Updater {
-    redis_actor: Addr<Unsync, RedisActor>,
+    redis_actor: Redis,
+    config: UpdaterConfig,
+    /// writes sent to redis and not answered yet
+    pending: usize,
+    /// keys of writes waiting for `pending` to drop below capacity, oldest first
+    backlog: VecDeque<String>,
+    /// the waiting write of every key in `backlog`
+    backlogged: HashMap<String, UpdateRedis>,
+    drains: Vec<oneshot::Sender<usize>>,
+    registered: bool,
+    /// this updater took the spill log and is writing its entries
+    replaying: bool,
+    /// taken entries waiting to be written, like the backlog
+    replay: VecDeque<UpdateRedis>,
+    replay_in_flight: usize,
+    /// taken entries that failed again, handed back to the spill log when the replay ends
+    unreplayed: Vec<UpdateRedis>,
+    /// no new replays once draining for shutdown
+    draining: bool,
 }
 
 impl Updater {
-    pub fn start(redis_actor: Addr<Unsync, RedisActor>) -> Addr<Unsync, Updater> {
-        Supervisor::start(|_| Updater { redis_actor })
+    pub fn start(redis_actor: Redis, config: UpdaterConfig) -> Addr<Unsync, Updater> {
+        Supervisor::start(|_| Updater {
+            redis_actor,
+            config,
+            pending: 0,
+            backlog: VecDeque::new(),
+            backlogged: HashMap::new(),
+            drains: Vec::new(),
+            registered: false,
+            replaying: false,
+            replay: VecDeque::new(),
+            replay_in_flight: 0,
+            unreplayed: Vec::new(),
+            draining: false,
+        })
+    }
+
+    fn queue_depth(&self) -> QueueDepth {
+        QueueDepth {
+            in_flight: self.pending,
+            backlog: self.backlog.len(),
+            capacity: self.config.queue_capacity,
+            policy: self.config.queue_policy,
+        }
+    }
+
+    fn write_done(&mut self, ctx: &mut Context<Self>) {
+        self.pending -= 1;
+        if let Some(msg) = self
+            .backlog
+            .pop_front()
+            .and_then(|key| self.backlogged.remove(&key))
+        {
+            // backlogged writes were already answered
+            ctx.spawn(self.write(msg, false).map_err(|_, _, _| ()));
+        } else {
+            self.feed_replay(ctx);
+            if self.pending == 0 {
+                self.finish_drains();
+            }
+        }
+    }
+
+    /* keeps the write for later, `false` when the backlog is full */
+    fn backlog(&mut self, msg: UpdateRedis) -> bool {
+        let key = msg.redis_key();
+        if let Some(queued) = self.backlogged.get_mut(&key) {
+            coalesce(queued, msg);
+            return true;
+        }
+        if self.backlog.len() < self.config.queue_capacity {
+            self.backlog.push_back(key.clone());
+            self.backlogged.insert(key, msg);
+            return true;
+        }
+        false
+    }
+
+    fn replica_copy(&self, msg: &UpdateRedis) -> Option<UpdateRedis> {
+        if self.config.replicas.is_empty() {
+            None
+        } else {
+            Some(msg.clone())
+        }
+    }
+
+    /* only writes accepted for the primary are fanned out */
+    fn replicate(&self, copy: Option<UpdateRedis>) {
+        if let Some(msg) = copy {
+            let queued = Instant::now();
+            for replica in &self.config.replicas {
+                replica.do_send(Replicate {
+                    queued,
+                    msg: msg.clone(),
+                });
+            }
+        }
+    }
+
+    /* answered once the write is in the spill log, counted as pending until then */
+    fn spill(
+        &mut self,
+        spill: SpillLog,
+        msg: UpdateRedis,
+    ) -> impl ActorFuture<Item = (), Error = Error, Actor = Self> {
+        if !spill.is_spilling() {
+            warn!("redis unavailable, spilling writes");
+        }
+        self.pending += 1;
+        spill
+            .addr
+            .send(Append(msg))
+            .into_actor(self)
+            .then(|r, act, ctx| {
+                act.write_done(ctx);
+                ::actix::fut::result(match r {
+                    Ok(Ok(())) => Ok(()),
+                    Ok(Err(e)) => Err(Error::Spill(e.to_string())),
+                    Err(e) => Err(e.into()),
+                })
+            })
+    }
+
+    /* takes the spill log once redis answers, unless another updater replays it already */
+    fn replay_spill(&mut self, ctx: &mut Context<Self>) {
+        let spill = match self.config.spill {
+            Some(ref spill) if spill.is_spilling() && !self.replaying && !self.draining => {
+                spill.clone()
+            }
+            _ => return,
+        };
+
+        let f = self
+            .redis_actor
+            .as_redis_handle()
+            .ping()
+            .map_err(|e| debug!("redis still unavailable: {}", e))
+            .and_then(move |_| {
+                spill
+                    .addr
+                    .send(Take)
+                    .map_err(|e| error!("spill log gone: {}", e))
+                    .and_then(|r| r.map_err(|e| error!("unable to read spill log: {}", e)))
+            })
+            .into_actor(self)
+            .then(|r, act, ctx| {
+                match r {
+                    Ok(Some(ref entries)) if entries.is_empty() => {
+                        debug!("spill log replayed, writing to redis again")
+                    }
+                    Ok(Some(entries)) => {
+                        info!("replaying {} spilled writes", entries.len());
+                        act.replaying = true;
+                        act.replay.extend(entries);
+                        act.feed_replay(ctx);
+                    }
+                    _ => (),
+                }
+                ::actix::fut::ok(())
+            });
+        ctx.spawn(f);
+    }
+
+    /* replayed entries go through the same bounded path as new writes */
+    fn feed_replay(&mut self, ctx: &mut Context<Self>) {
+        if !self.replaying {
+            return;
+        }
+        while self.pending < self.config.queue_capacity {
+            match self.replay.pop_front() {
+                Some(msg) => {
+                    self.replay_in_flight += 1;
+                    ctx.spawn(self.write(msg, true).map_err(|_, _, _| ()));
+                }
+                None => break,
+            }
+        }
+        if self.replay.is_empty() && self.replay_in_flight == 0 {
+            self.finish_replay(ctx);
+        }
+    }
+
+    /* stops at the first failure, entries not written go back to the spill log */
+    fn abort_replay(&mut self, msg: Option<UpdateRedis>) {
+        self.unreplayed.extend(msg);
+        self.unreplayed.extend(self.replay.drain(..));
+    }
+
+    fn finish_replay(&mut self, ctx: &mut Context<Self>) {
+        let spill = match self.config.spill {
+            Some(ref spill) => spill.clone(),
+            None => return,
+        };
+        self.replaying = false;
+        let unwritten = mem::take(&mut self.unreplayed);
+        let complete = unwritten.is_empty();
+        if !complete {
+            warn!(
+                "{} spilled writes not replayed, redis unavailable again",
+                unwritten.len()
+            );
+        }
+
+        self.pending += 1;
+        ctx.spawn(spill.addr.send(Replayed(unwritten)).into_actor(self).then(
+            move |r, act, ctx| {
+                if let Err(e) = r {
+                    error!("spill log gone: {}", e);
+                }
+                act.write_done(ctx);
+                // until nothing was spilled meanwhile
+                if complete {
+                    act.replay_spill(ctx);
+                }
+                ::actix::fut::ok(())
+            },
+        ));
+    }
+
+    fn retry_spill(&mut self, ctx: &mut Context<Self>) {
+        self.replay_spill(ctx);
+        ctx.run_later(Duration::from_secs(SPILL_RETRY_SECS), |act, ctx| {
+            act.retry_spill(ctx)
+        });
+    }
+
+    fn finish_drains(&mut self) {
+        let pending = self.pending + self.backlog.len();
+        for drain in self.drains.drain(..) {
+            let _ = drain.send(pending);
+        }
     }
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UpdateMap {
     pub collection: String,
     pub key: String,
     pub value: HashMap<String, String>,
+    /// typed form of `value`, published instead of it when present
+    pub document: Option<Value>,
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UpdateVal {
     pub collection: String,
     pub key: String,
     pub value: String,
+    /// seconds until the key expires, kept until overwritten when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateIncr {
+    pub collection: String,
+    pub key: String,
+    /// hash fields and what they are incremented by
+    pub value: HashMap<String, i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum UpdateRedis {
     UpdateRedisMap(UpdateMap),
     UpdateRedisVal(UpdateVal),
+    UpdateRedisIncr(UpdateIncr),
+}
+
+impl UpdateRedis {
+    pub fn redis_key(&self) -> String {
+        match self {
+            UpdateRedis::UpdateRedisMap(u) => format!("{}.{}", u.collection, u.key),
+            UpdateRedis::UpdateRedisVal(u) => format!("{}.{}", u.collection, u.key),
+            UpdateRedis::UpdateRedisIncr(u) => format!("{}.{}", u.collection, u.key),
+        }
+    }
+
+    /// Increments add up in any order, so they are never written again on top of themselves.
+    pub fn is_increment(&self) -> bool {
+        match self {
+            UpdateRedis::UpdateRedisIncr(_) => true,
+            _ => false,
+        }
+    }
 }
 
 impl Message for UpdateRedis {
@@ -61,12 +396,126 @@ impl Actor for Updater {
             .wait(ctx);
 
         //ctx.spawn(r);
+
+        if !self.registered {
+            self.registered = true;
+            Arbiter::system_registry()
+                .get::<Shutdown>()
+                .do_send(Register(ctx.address()));
+        }
+
+        if self.config.spill.is_some() {
+            // a replay cut short by a restart is handed back first
+            if self.replaying {
+                self.finish_replay(ctx);
+            }
+            self.retry_spill(ctx);
+        }
     }
 }
 
 impl Supervised for Updater {
     fn restarting(&mut self, _: &mut Self::Context) {
         warn!("restarting!");
+        // the writes in flight went away with the old context
+        self.pending = 0;
+        self.replay_in_flight = 0;
+        self.abort_replay(None);
+    }
+}
+
+/// Writes held by an updater.
+#[derive(Serialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+pub struct QueueDepth {
+    pub in_flight: usize,
+    pub backlog: usize,
+    pub capacity: usize,
+    #[serde(skip)]
+    pub policy: QueuePolicy,
+}
+
+impl QueueDepth {
+    /// New writes are refused, or with `QueuePolicy::Coalesce` only those of keys not
+    /// backlogged yet.
+    pub fn is_full(&self) -> bool {
+        match self.policy {
+            QueuePolicy::Reject => self.in_flight >= self.capacity,
+            QueuePolicy::Coalesce => {
+                self.in_flight >= self.capacity && self.backlog >= self.capacity
+            }
+        }
+    }
+}
+
+/// Answered as soon as the updater gets to it, so a reply means its mailbox is being processed.
+pub struct Alive;
+
+impl Message for Alive {
+    type Result = QueueDepth;
+}
+
+impl Handler<Alive> for Updater {
+    type Result = MessageResult<Alive>;
+
+    fn handle(&mut self, _: Alive, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.queue_depth())
+    }
+}
+
+/// Answered once every write handed to redis so far got its reply, or after `deadline`
+/// with the number of writes still waiting.
+pub struct Drain {
+    pub deadline: Duration,
+}
+
+impl Message for Drain {
+    type Result = Result<usize, ()>;
+}
+
+impl Handler<Drain> for Updater {
+    type Result = ResponseFuture<usize, ()>;
+
+    fn handle(&mut self, msg: Drain, ctx: &mut Self::Context) -> Self::Result {
+        self.draining = true;
+        if self.replaying {
+            // what is left of the replay goes back to the spill log
+            self.abort_replay(None);
+            self.feed_replay(ctx);
+        }
+        if self.pending == 0 {
+            return Box::new(future::ok(0));
+        }
+        debug!("draining {} pending writes", self.pending);
+
+        let (tx, rx) = oneshot::channel();
+        self.drains.push(tx);
+        ctx.run_later(msg.deadline, |act, _| act.finish_drains());
+
+        Box::new(rx.map_err(|_| ()))
+    }
+}
+
+/// Answered with the status of every replica writes are copied to.
+pub struct Replication;
+
+impl Message for Replication {
+    type Result = Result<Vec<ReplicaStatus>, ()>;
+}
+
+impl Handler<Replication> for Updater {
+    type Result = ResponseFuture<Vec<ReplicaStatus>, ()>;
+
+    fn handle(&mut self, _: Replication, _: &mut Self::Context) -> Self::Result {
+        Box::new(
+            future::join_all(
+                self.config
+                    .replicas
+                    .iter()
+                    .map(|replica| replica.send(Status))
+                    .collect::<Vec<_>>(),
+            )
+            .map_err(|e| warn!("replica gone: {}", e)),
+        )
     }
 }
 
@@ -74,6 +523,12 @@ impl Supervised for Updater {
 pub enum Error {
     #[fail(display = "MailboxError {}", _0)]
     MailboxError(actix::MailboxError),
+    #[fail(display = "redis {}", _0)]
+    Redis(String),
+    #[fail(display = "write queue full")]
+    QueueFull,
+    #[fail(display = "spill {}", _0)]
+    Spill(String),
 }
 
 impl From<MailboxError> for Error {
@@ -82,6 +537,11 @@ impl From<MailboxError> for Error {
     }
 }
 
+fn now_in_millis() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() * 1000 + u64::from(now.subsec_millis())
+}
+
 fn to_hmset_command(msg: UpdateMap) -> Command {
     debug!("preparing command for {:?}", msg);
 
@@ -97,10 +557,134 @@ fn to_hmset_command(msg: UpdateMap) -> Command {
     Command(RespValue::Array(msg_vec))
 }
 
+/* a field changing between a plain and a JSON-encoded value must not be kept in both forms */
+fn to_hdel_command(msg: &UpdateMap) -> Option<Command> {
+    if msg.value.is_empty() {
+        return None;
+    }
+    let mut msg_vec: Vec<RespValue> = Vec::with_capacity(2 + msg.value.len());
+    msg_vec.push("HDEL".into());
+    msg_vec.push(format!("{}.{}", msg.collection, msg.key).into());
+
+    for key in msg.value.keys() {
+        let other = if key.ends_with(JSON_FIELD_SUFFIX) {
+            key[..key.len() - JSON_FIELD_SUFFIX.len()].to_string()
+        } else {
+            format!("{}{}", key, JSON_FIELD_SUFFIX)
+        };
+        msg_vec.push(other.into());
+    }
+
+    Some(Command(RespValue::Array(msg_vec)))
+}
+
+#[derive(Serialize, Debug)]
+struct Published<'a> {
+    collection: &'a str,
+    key: &'a str,
+    data: Value,
+}
+
+/* counters are not announced */
+fn to_published(msg: &UpdateRedis) -> Option<Published<'_>> {
+    Some(match msg {
+        UpdateRedis::UpdateRedisMap(u) => Published {
+            collection: &u.collection,
+            key: &u.key,
+            data: match u.document {
+                Some(ref document) => document.clone(),
+                None => serde_json::to_value(&u.value).unwrap_or(Value::Null),
+            },
+        },
+        UpdateRedis::UpdateRedisVal(u) => Published {
+            collection: &u.collection,
+            key: &u.key,
+            data: serde_json::from_str(&u.value).unwrap_or_else(|_| Value::String(u.value.clone())),
+        },
+        UpdateRedis::UpdateRedisIncr(_) => return None,
+    })
+}
+
+fn to_publish_command(publish: &Publish, msg: &Published) -> Result<Command, serde_json::Error> {
+    Ok(match publish {
+        Publish::Channel(channel) => Command(resp_array![
+            "PUBLISH",
+            channel.as_str(),
+            serde_json::to_string(msg)?
+        ]),
+        Publish::Stream { key, maxlen } => {
+            let mut cmd: Vec<RespValue> = vec!["XADD".into(), key.as_str().into()];
+            if let Some(maxlen) = maxlen {
+                cmd.extend(vec!["MAXLEN".into(), "~".into(), maxlen.to_string().into()]);
+            }
+            cmd.extend(vec![
+                "*".into(),
+                "collection".into(),
+                msg.collection.into(),
+                "key".into(),
+                msg.key.into(),
+                "data".into(),
+                serde_json::to_string(&msg.data)?.into(),
+            ]);
+            Command(RespValue::Array(cmd))
+        }
+    })
+}
+
+/// Folds a newer write to the same key into `queued`: hash fields are merged the way `HMSET`
+/// would, increments added up, values replaced.
+pub fn coalesce(queued: &mut UpdateRedis, msg: UpdateRedis) {
+    match (queued, msg) {
+        (UpdateRedis::UpdateRedisMap(queued), UpdateRedis::UpdateRedisMap(msg)) => {
+            queued.value.extend(msg.value);
+            queued.document = match (queued.document.take(), msg.document) {
+                (Some(Value::Object(mut queued)), Some(Value::Object(msg))) => {
+                    queued.extend(msg);
+                    Some(Value::Object(queued))
+                }
+                (queued, msg) => msg.or(queued),
+            };
+        }
+        (UpdateRedis::UpdateRedisIncr(queued), UpdateRedis::UpdateRedisIncr(msg)) => {
+            for (field, by) in msg.value {
+                let total = queued.value.entry(field).or_insert(0);
+                *total = total.saturating_add(by);
+            }
+        }
+        (queued, msg) => *queued = msg,
+    }
+}
+
+fn to_hincrby_commands(msg: UpdateIncr) -> Vec<Command> {
+    let key = format!["{}.{}", msg.collection, msg.key];
+    msg.value
+        .into_iter()
+        .map(|(field, by)| Command(resp_array!["HINCRBY", key.as_str(), field, by.to_string()]))
+        .collect()
+}
+
 fn to_set_command(msg: UpdateVal) -> Command {
     debug!("preparing command for {:?}", msg);
     let key = format!["{}.{}", msg.collection, msg.key];
-    Command(resp_array!["SET", key, msg.value])
+    match msg.ttl {
+        Some(ttl) => Command(resp_array!["SET", key, msg.value, "EX", ttl.to_string()]),
+        None => Command(resp_array!["SET", key, msg.value]),
+    }
+}
+
+/// Every command the primary gets for `msg`, announcements aside.
+pub fn write_commands(msg: UpdateRedis) -> Vec<Command> {
+    match msg {
+        UpdateRedis::UpdateRedisMap(u) => {
+            let mut commands = vec![Command(resp_array!["SADD", "active_nodes", &u.key])];
+            commands.extend(to_hdel_command(&u));
+            commands.push(to_hmset_command(u));
+            commands.extend(ChangeMarker::touch_commands(now_in_millis()));
+            commands
+        }
+        UpdateRedis::UpdateRedisVal(u) => vec![to_set_command(u)],
+        UpdateRedis::UpdateRedisIncr(u) => to_hincrby_commands(u),
+    }
 }
 
 impl Handler<UpdateRedis> for Updater {
@@ -111,24 +695,299 @@ impl Handler<UpdateRedis> for Updater {
         msg: UpdateRedis,
         _: &mut Self::Context,
     ) -> <Self as Handler<UpdateRedis>>::Result {
+        let copy = self.replica_copy(&msg);
+
+        if let Some(spill) = self.config.spill.clone() {
+            if spill.is_spilling() {
+                self.replicate(copy);
+                return ActorResponse::async(self.spill(spill, msg));
+            }
+        }
+        if self.pending < self.config.queue_capacity {
+            self.replicate(copy);
+            return ActorResponse::async(self.write(msg, false));
+        }
+        if self.config.queue_policy == QueuePolicy::Coalesce && self.backlog(msg) {
+            self.replicate(copy);
+            return ActorResponse::reply(Ok(()));
+        }
+        debug!("write queue full: {:?}", self.queue_depth());
+        ActorResponse::reply(Err(Error::QueueFull))
+    }
+}
+
+impl Updater {
+    /* `replayed` writes were taken from the spill log */
+    fn write(
+        &mut self,
+        msg: UpdateRedis,
+        replayed: bool,
+    ) -> impl ActorFuture<Item = (), Error = Error, Actor = Self> {
+        let retry = self
+            .config
+            .spill
+            .as_ref()
+            .map(|spill| (spill.clone(), msg.clone()));
         let redis_actor = &self.redis_actor;
 
-        if let UpdateRedis::UpdateRedisMap(ref msg) = msg {
-            redis_actor.do_send(Command(resp_array!["SADD", "active_nodes", &msg.key]));
+        let touch_nodes = match msg {
+            UpdateRedis::UpdateRedisMap(ref msg) => {
+                redis_actor.do_send(Command(resp_array!["SADD", "active_nodes", &msg.key]));
+                if let Some(cmd) = to_hdel_command(msg) {
+                    redis_actor.do_send(cmd);
+                }
+                true
+            }
+            // ping-me results are merged into node listings
+            UpdateRedis::UpdateRedisVal(ref msg) => msg.collection == "pingme",
+            UpdateRedis::UpdateRedisIncr(_) => false,
+        };
+
+        let mut announcements = Vec::with_capacity(self.config.publish.len());
+        let published = if self.config.publish.is_empty() {
+            None
+        } else {
+            to_published(&msg)
+        };
+        if let Some(published) = published {
+            for publish in &self.config.publish {
+                match to_publish_command(publish, &published) {
+                    Ok(cmd) => announcements.push(cmd),
+                    Err(e) => warn!(
+                        "unable to publish {}.{}: {}",
+                        published.collection, published.key, e
+                    ),
+                }
+            }
         }
 
-        let f = redis_actor
-            .send(match msg {
-                UpdateRedis::UpdateRedisMap(u) => to_hmset_command(u),
-                UpdateRedis::UpdateRedisVal(u) => to_set_command(u),
-            })
+        let command = match msg {
+            UpdateRedis::UpdateRedisMap(u) => to_hmset_command(u),
+            UpdateRedis::UpdateRedisVal(u) => to_set_command(u),
+            // the reply to the last increment stands for all of them
+            UpdateRedis::UpdateRedisIncr(u) => {
+                let mut commands = to_hincrby_commands(u);
+                let last = commands.pop();
+                for cmd in commands {
+                    redis_actor.do_send(cmd);
+                }
+                last.unwrap_or_else(|| Command(resp_array!["PING"]))
+            }
+        };
+
+        self.pending += 1;
+        redis_actor
+            .send(command)
             .into_actor(self)
-            .map_err(|e, _, _| {
-                error!("update key error {:?}", &e);
-                e.into()
+            .then(move |r, act, ctx| {
+                if replayed {
+                    act.replay_in_flight -= 1;
+                }
+
+                let e = match r {
+                    Ok(Ok(resp)) => {
+                        debug!("resp={:?}", resp);
+                        if touch_nodes {
+                            for cmd in ChangeMarker::touch_commands(now_in_millis()) {
+                                act.redis_actor.do_send(cmd);
+                            }
+                        }
+                        for cmd in announcements {
+                            act.redis_actor.do_send(cmd);
+                        }
+                        act.write_done(ctx);
+                        return Either::A(::actix::fut::ok(()));
+                    }
+                    Ok(Err(e)) => Error::Redis(e.to_string()),
+                    Err(e) => e.into(),
+                };
+                match retry {
+                    Some((_, msg)) if replayed => {
+                        debug!("replayed write failed: {}", e);
+                        act.abort_replay(Some(msg));
+                        act.write_done(ctx);
+                        Either::A(::actix::fut::ok(()))
+                    }
+                    Some((spill, msg)) => {
+                        debug!("update key error {}, spilling", e);
+                        // pending until spilled
+                        let spilled = act.spill(spill, msg);
+                        act.write_done(ctx);
+                        Either::B(spilled)
+                    }
+                    None => {
+                        error!("update key error {:?}", e);
+                        act.write_done(ctx);
+                        Either::A(::actix::fut::err(e))
+                    }
+                }
             })
-            .map(|r, _, _| debug!("resp={:?}", r));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_update() -> UpdateRedis {
+        let mut value = HashMap::new();
+        value.insert("num_cores".to_string(), "4".to_string());
+
+        UpdateRedis::UpdateRedisMap(UpdateMap {
+            collection: "nodeinfo".into(),
+            key: "0xab".into(),
+            value,
+            document: Some(json!({"cliid": "0xab", "num_cores": 4})),
+        })
+    }
+
+    #[test]
+    fn published_document() {
+        let msg = node_update();
+        let published = to_published(&msg).unwrap();
+        assert_eq!(
+            serde_json::to_value(&published).unwrap(),
+            json!({"collection": "nodeinfo", "key": "0xab", "data": {"cliid": "0xab", "num_cores": 4}})
+        );
+
+        let msg = UpdateRedis::UpdateRedisVal(UpdateVal {
+            collection: "p2pstats".into(),
+            key: "0xab".into(),
+            value: "{\"peers\": []}".into(),
+            ttl: None,
+        });
+        assert_eq!(to_published(&msg).unwrap().data, json!({"peers": []}));
+    }
+
+    #[test]
+    fn coalesced_writes() {
+        let mut queued = node_update();
+        let mut value = HashMap::new();
+        value.insert("num_cores".to_string(), "8".to_string());
+        value.insert("completed".to_string(), "3".to_string());
+        let newer = UpdateRedis::UpdateRedisMap(UpdateMap {
+            collection: "nodeinfo".into(),
+            key: "0xab".into(),
+            value,
+            document: Some(json!({"num_cores": 8, "completed": 3})),
+        });
+        assert_eq!(queued.redis_key(), newer.redis_key());
+
+        coalesce(&mut queued, newer);
+        match queued {
+            UpdateRedis::UpdateRedisMap(u) => {
+                assert_eq!(u.value["num_cores"], "8");
+                assert_eq!(u.value["completed"], "3");
+                assert_eq!(
+                    u.document,
+                    Some(json!({"cliid": "0xab", "num_cores": 8, "completed": 3}))
+                );
+            }
+            v => panic!("unexpected {:?}", v),
+        }
+
+        let p2p = UpdateRedis::UpdateRedisVal(UpdateVal {
+            collection: "p2pstats".into(),
+            key: "0xab".into(),
+            value: "[]".into(),
+            ttl: None,
+        });
+        assert_ne!(node_update().redis_key(), p2p.redis_key());
+    }
+
+    #[test]
+    fn coalesced_increments() {
+        let increment = |field: &str, by| {
+            let mut value = HashMap::new();
+            value.insert(field.to_string(), by);
+            UpdateRedis::UpdateRedisIncr(UpdateIncr {
+                collection: "stats".into(),
+                key: "proto_ver".into(),
+                value,
+            })
+        };
+        let mut queued = increment("1", 1);
+        coalesce(&mut queued, increment("1", 2));
+        coalesce(&mut queued, increment("other", 1));
+        assert!(queued.is_increment());
+        assert!(to_published(&queued).is_none());
+
+        let mut commands: Vec<_> = write_commands(queued)
+            .into_iter()
+            .map(|Command(cmd)| cmd)
+            .collect();
+        commands.sort_by_key(|cmd| format!("{:?}", cmd));
+        assert_eq!(
+            commands,
+            vec![
+                resp_array!["HINCRBY", "stats.proto_ver", "other", "1"],
+                resp_array!["HINCRBY", "stats.proto_ver", "1", "3"],
+            ]
+        );
+    }
+
+    #[test]
+    fn queue_fullness() {
+        let mut depth = QueueDepth {
+            in_flight: 2,
+            backlog: 1,
+            capacity: 2,
+            policy: QueuePolicy::Reject,
+        };
+        assert!(depth.is_full());
+        depth.policy = QueuePolicy::Coalesce;
+        assert!(!depth.is_full());
+        depth.backlog = 2;
+        assert!(depth.is_full());
+    }
+
+    #[test]
+    fn other_field_forms_deleted() {
+        let mut value = HashMap::new();
+        value.insert("extra:json".to_string(), "[1]".to_string());
+        let msg = UpdateMap {
+            collection: "nodeinfo".into(),
+            key: "0xab".into(),
+            value,
+            document: None,
+        };
+        assert_eq!(
+            to_hdel_command(&msg).map(|Command(cmd)| cmd),
+            Some(resp_array!["HDEL", "nodeinfo.0xab", "extra"])
+        );
+    }
 
-        ActorResponse::async(f)
+    #[test]
+    fn stream_command() {
+        let msg = node_update();
+        let published = to_published(&msg).unwrap();
+        let publish = Publish::Stream {
+            key: "nodes".into(),
+            maxlen: Some(1000),
+        };
+        let Command(cmd) = to_publish_command(&publish, &published).unwrap();
+        let args: Vec<String> = match cmd {
+            RespValue::Array(args) => args
+                .into_iter()
+                .map(|arg| match arg {
+                    RespValue::BulkString(s) => String::from_utf8(s).unwrap(),
+                    v => panic!("unexpected {:?}", v),
+                })
+                .collect(),
+            v => panic!("unexpected {:?}", v),
+        };
+        assert_eq!(
+            &args[..8],
+            &[
+                "XADD",
+                "nodes",
+                "MAXLEN",
+                "~",
+                "1000",
+                "*",
+                "collection",
+                "nodeinfo"
+            ]
+        );
     }
 }
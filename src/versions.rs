@@ -0,0 +1,262 @@
+use schemars::JsonSchema;
+use semver::Version;
+use std::collections::{BTreeMap, HashMap};
+
+/// Lenient semver parsing of reported node versions: accepts build metadata
+/// (`0.16.2+dev1.g6468183d6`) as well as short (`0.2`) and non-semver suffixed
+/// (`0.16.2.dev12`) versions, which are cut down to their numeric part.
+pub fn parse_version(s: &str) -> Option<Version> {
+    let s = s.trim().trim_start_matches('v');
+    if let Ok(version) = Version::parse(s) {
+        return Some(version);
+    }
+
+    let core_len = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let mut parts = s[..core_len]
+        .split('.')
+        .filter(|part| !part.is_empty())
+        .take(3)
+        .map(|part| part.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if parts.is_empty() {
+        return None;
+    }
+    parts.resize(3, 0);
+
+    Some(Version::new(parts[0], parts[1], parts[2]))
+}
+
+/* `27`, `"27"` or `"27-dev"` as stored in `protocol_version_*` */
+fn parse_protocol_version(s: &str) -> Option<u64> {
+    let digits = s.trim().trim_matches('"');
+    let len = digits
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(digits.len());
+    digits[..len].parse().ok()
+}
+
+static PROTOCOLS: &[&str] = &["p2p", "task"];
+
+#[derive(Debug, Clone, Default)]
+pub struct VersionPolicy {
+    /// nodes running older versions are reported as deprecated
+    pub min_version: Option<Version>,
+    /// nodes speaking an older p2p or task protocol are reported as incompatible
+    pub min_protocol_version: Option<u64>,
+}
+
+impl VersionPolicy {
+    pub fn new(
+        min_version: Option<&str>,
+        min_protocol_version: Option<u64>,
+    ) -> Result<Self, String> {
+        let min_version = match min_version {
+            Some(v) => Some(parse_version(v).ok_or_else(|| format!("invalid min_version: {}", v))?),
+            None => None,
+        };
+        Ok(VersionPolicy {
+            min_version,
+            min_protocol_version,
+        })
+    }
+}
+
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+struct VersionCount {
+    version: String,
+    count: u64,
+}
+
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+struct FlaggedNode {
+    cliid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    node_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    protocol_versions: BTreeMap<String, String>,
+}
+
+impl FlaggedNode {
+    fn new(node: &HashMap<String, String>) -> Self {
+        FlaggedNode {
+            cliid: node.get("cliid").cloned().unwrap_or_default(),
+            node_name: node.get("node_name").cloned(),
+            version: node.get("version").cloned(),
+            protocol_versions: PROTOCOLS
+                .iter()
+                .filter_map(|protocol| {
+                    node.get(&format!("protocol_version_{}", protocol))
+                        .map(|v| (protocol.to_string(), v.clone()))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// What `/v1/versions` responds with.
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct VersionReport {
+    nodes: u64,
+    #[serde(serialize_with = "serialize_version")]
+    #[schemars(with = "Option<String>")]
+    min_version: Option<Version>,
+    min_protocol_version: Option<u64>,
+    /// newest first, `unknown` for missing or unparseable versions
+    versions: Vec<VersionCount>,
+    protocols: BTreeMap<String, BTreeMap<String, u64>>,
+    deprecated: Vec<FlaggedNode>,
+    incompatible: Vec<FlaggedNode>,
+    #[serde(skip)]
+    counts: BTreeMap<Option<Version>, u64>,
+}
+
+fn serialize_version<S: ::serde::Serializer>(
+    version: &Option<Version>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match version {
+        Some(version) => serializer.collect_str(version),
+        None => serializer.serialize_none(),
+    }
+}
+
+/* groups builds of the same release together */
+fn release(mut version: Version) -> Version {
+    version.build.clear();
+    version
+}
+
+impl VersionReport {
+    pub fn new(policy: &VersionPolicy) -> Self {
+        VersionReport {
+            nodes: 0,
+            min_version: policy.min_version.clone(),
+            min_protocol_version: policy.min_protocol_version,
+            versions: Vec::new(),
+            protocols: BTreeMap::new(),
+            deprecated: Vec::new(),
+            incompatible: Vec::new(),
+            counts: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, node: &HashMap<String, String>) {
+        self.nodes += 1;
+
+        let version = node
+            .get("version")
+            .and_then(|v| parse_version(v))
+            .map(release);
+        if let (Some(min), Some(version)) = (self.min_version.as_ref(), version.as_ref()) {
+            if version < min {
+                self.deprecated.push(FlaggedNode::new(node));
+            }
+        }
+        *self.counts.entry(version).or_insert(0) += 1;
+
+        let mut incompatible = false;
+        for protocol in PROTOCOLS {
+            if let Some(value) = node.get(&format!("protocol_version_{}", protocol)) {
+                *self
+                    .protocols
+                    .entry(protocol.to_string())
+                    .or_default()
+                    .entry(value.clone())
+                    .or_insert(0) += 1;
+
+                if let (Some(min), Some(v)) =
+                    (self.min_protocol_version, parse_protocol_version(value))
+                {
+                    incompatible |= v < min;
+                }
+            }
+        }
+        if incompatible {
+            self.incompatible.push(FlaggedNode::new(node));
+        }
+    }
+
+    pub fn finish(mut self) -> Self {
+        self.versions = self
+            .counts
+            .iter()
+            .rev()
+            .map(|(version, count)| VersionCount {
+                version: version
+                    .as_ref()
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| "unknown".into()),
+                count: *count,
+            })
+            .collect();
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(version: &str, p2p: &str) -> HashMap<String, String> {
+        vec![
+            ("cliid", "n"),
+            ("version", version),
+            ("protocol_version_p2p", p2p),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn parse_versions() {
+        assert_eq!(
+            parse_version("0.16.2+dev1.g6468183d6").map(release),
+            Some(Version::new(0, 16, 2))
+        );
+        assert_eq!(parse_version("0.2"), Some(Version::new(0, 2, 0)));
+        assert_eq!(parse_version("v0.16.2.dev12"), Some(Version::new(0, 16, 2)));
+        assert!(parse_version("0.17.0-rc1").unwrap() < Version::new(0, 17, 0));
+        assert_eq!(parse_version("unknown"), None);
+        assert_eq!(parse_protocol_version("\"27-dev\""), Some(27));
+    }
+
+    #[test]
+    fn report() {
+        let policy = VersionPolicy::new(Some("0.16"), Some(27)).unwrap();
+        let mut report = VersionReport::new(&policy);
+        report.add(&node("0.16.2+dev1.g6468183d6", "27"));
+        report.add(&node("0.16.2", "27"));
+        report.add(&node("0.15.1", "26"));
+        report.add(&node("garbage", "27"));
+        let report = report.finish();
+
+        assert_eq!(report.nodes, 4);
+        assert_eq!(
+            report.versions,
+            vec![
+                VersionCount {
+                    version: "0.16.2".into(),
+                    count: 2,
+                },
+                VersionCount {
+                    version: "0.15.1".into(),
+                    count: 1,
+                },
+                VersionCount {
+                    version: "unknown".into(),
+                    count: 1,
+                },
+            ]
+        );
+        assert_eq!(report.protocols["p2p"]["27"], 3);
+        assert_eq!(report.deprecated.len(), 1);
+        assert_eq!(report.deprecated[0].version, Some("0.15.1".into()));
+        assert_eq!(report.incompatible.len(), 1);
+        assert_eq!(report.incompatible[0].protocol_versions["p2p"], "26");
+    }
+}
@@ -0,0 +1,329 @@
+use actix::prelude::*;
+use actix_web::client;
+use futures::future;
+use futures::prelude::*;
+use futures::sync::oneshot;
+use serde_json::Value;
+use shutdown::{RegisterWebhook, Shutdown};
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+const SEND_TIMEOUT_SECS: u64 = 10;
+const MAX_BACKOFF_SECS: u64 = 300;
+
+fn default_batch_size() -> usize {
+    100
+}
+
+fn default_flush_interval_secs() -> u64 {
+    5
+}
+
+fn default_max_queued() -> usize {
+    10000
+}
+
+/// An HTTP collector reports are forwarded to, configured as a `[[webhooks]]` table
+/// of `golem-monitor.toml`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// reports per request
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// a partial batch is sent after this long
+    #[serde(default = "default_flush_interval_secs")]
+    pub flush_interval_secs: u64,
+    /// report types forwarded, e.g. `Login`, `Stats`, `P2PSnapshot`; every type when empty
+    #[serde(default)]
+    pub types: Vec<String>,
+    /// reports kept while the collector is failing, the oldest are dropped beyond that
+    #[serde(default = "default_max_queued")]
+    pub max_queued: usize,
+}
+
+/// A report as stored: the node info for node reports, the snapshot for `P2PSnapshot`,
+/// `null` for `Logout`.
+#[derive(Serialize, Clone, Debug)]
+pub struct Report {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub cliid: String,
+    /// millis since epoch
+    pub received: u64,
+    pub ip: Option<IpAddr>,
+    pub data: Value,
+}
+
+impl Message for Report {
+    type Result = ();
+}
+
+/// Posts reports to `config.url` as JSON arrays of up to `batch_size`. Failed batches
+/// are retried, ahead of newer reports, with exponential backoff. On shutdown whatever
+/// is queued is sent right away.
+pub struct Webhook {
+    config: WebhookConfig,
+    queue: VecDeque<Report>,
+    /// reports in the batch being sent
+    sending: usize,
+    failures: u32,
+    retry_at: Option<Instant>,
+    dropped: u64,
+    drains: Vec<oneshot::Sender<usize>>,
+}
+
+impl Webhook {
+    fn new(config: WebhookConfig) -> Self {
+        Webhook {
+            config,
+            queue: VecDeque::new(),
+            sending: 0,
+            failures: 0,
+            retry_at: None,
+            dropped: 0,
+            drains: Vec::new(),
+        }
+    }
+
+    fn backoff(&self) -> Duration {
+        let interval = cmp::max(self.config.flush_interval_secs, 1);
+        let backoff = interval.saturating_mul(1 << cmp::min(self.failures, 16));
+        Duration::from_secs(cmp::min(backoff, MAX_BACKOFF_SECS))
+    }
+
+    fn flush(&mut self, ctx: &mut Context<Self>) {
+        if self.sending > 0 {
+            return;
+        }
+        if self.queue.is_empty() {
+            return self.finish_drains();
+        }
+        if let Some(retry_at) = self.retry_at {
+            // no waiting out the backoff once shutting down
+            if retry_at > Instant::now() && self.drains.is_empty() {
+                return;
+            }
+        }
+
+        let n = cmp::min(cmp::max(self.config.batch_size, 1), self.queue.len());
+        let batch: Vec<Report> = self.queue.drain(..n).collect();
+
+        let mut request = client::post(&self.config.url);
+        for (name, value) in &self.config.headers {
+            request.header(name.as_str(), value.as_str());
+        }
+        let request = match request.json(&batch) {
+            Ok(request) => request,
+            Err(e) => {
+                self.dropped += batch.len() as u64;
+                return error!(
+                    "webhook {}: dropping batch of {} reports: {}",
+                    self.config.url,
+                    batch.len(),
+                    e
+                );
+            }
+        };
+        self.sending = batch.len();
+
+        let f = request
+            .send()
+            .timeout(Duration::from_secs(SEND_TIMEOUT_SECS))
+            .map_err(|e| e.to_string())
+            .and_then(|resp| {
+                if resp.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(format!("status {}", resp.status()))
+                }
+            })
+            .into_actor(self)
+            .then(move |r, act, ctx| {
+                act.sending = 0;
+                match r {
+                    Ok(()) => {
+                        act.failures = 0;
+                        act.retry_at = None;
+                        if act.queue.len() >= act.config.batch_size || !act.drains.is_empty() {
+                            act.flush(ctx);
+                        }
+                    }
+                    Err(e) => {
+                        act.failures += 1;
+                        let backoff = act.backoff();
+                        warn!(
+                            "webhook {}: {} reports not delivered ({}), retrying in {:?}",
+                            act.config.url,
+                            batch.len(),
+                            e,
+                            backoff
+                        );
+                        for report in batch.into_iter().rev() {
+                            act.queue.push_front(report);
+                        }
+                        act.trim();
+                        act.retry_at = Some(Instant::now() + backoff);
+                        ctx.run_later(backoff, |act, ctx| act.flush(ctx));
+                        // the collector is failing, no retrying it on shutdown
+                        act.finish_drains();
+                    }
+                }
+                ::actix::fut::ok(())
+            });
+        ctx.spawn(f);
+    }
+
+    fn flush_later(&mut self, ctx: &mut Context<Self>) {
+        let interval = Duration::from_secs(cmp::max(self.config.flush_interval_secs, 1));
+        ctx.run_later(interval, |act, ctx| {
+            act.flush(ctx);
+            act.flush_later(ctx);
+        });
+    }
+
+    fn finish_drains(&mut self) {
+        let queued = self.queue.len() + self.sending;
+        for drain in self.drains.drain(..) {
+            let _ = drain.send(queued);
+        }
+    }
+
+    fn trim(&mut self) {
+        while self.queue.len() > self.config.max_queued {
+            self.queue.pop_front();
+            self.dropped += 1;
+            if self.dropped % 1000 == 1 {
+                warn!(
+                    "webhook {}: queue full, {} reports dropped so far",
+                    self.config.url, self.dropped
+                );
+            }
+        }
+    }
+}
+
+impl Actor for Webhook {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        Arbiter::system_registry()
+            .get::<Shutdown>()
+            .do_send(RegisterWebhook(ctx.address()));
+        self.flush_later(ctx);
+    }
+}
+
+impl Handler<Report> for Webhook {
+    type Result = ();
+
+    fn handle(&mut self, msg: Report, ctx: &mut Self::Context) {
+        self.queue.push_back(msg);
+        self.trim();
+        if self.queue.len() >= self.config.batch_size {
+            self.flush(ctx);
+        }
+    }
+}
+
+/// Answered once every queued report is sent, with the number of reports left undelivered
+/// when the collector fails meanwhile or `deadline` passes.
+pub struct Flush {
+    pub deadline: Duration,
+}
+
+impl Message for Flush {
+    type Result = Result<usize, ()>;
+}
+
+impl Handler<Flush> for Webhook {
+    type Result = ResponseFuture<usize, ()>;
+
+    fn handle(&mut self, msg: Flush, ctx: &mut Self::Context) -> Self::Result {
+        if self.queue.is_empty() && self.sending == 0 {
+            return Box::new(future::ok(0));
+        }
+        debug!(
+            "webhook {}: flushing {} reports",
+            self.config.url,
+            self.queue.len()
+        );
+
+        let (tx, rx) = oneshot::channel();
+        self.drains.push(tx);
+        ctx.run_later(msg.deadline, |act, _| act.finish_drains());
+        self.flush(ctx);
+
+        Box::new(rx.map_err(|_| ()))
+    }
+}
+
+/// Every configured webhook, with the report types it takes.
+#[derive(Clone, Default)]
+pub struct Webhooks(Vec<(Vec<String>, Addr<Syn, Webhook>)>);
+
+impl Webhooks {
+    pub fn start(configs: &[WebhookConfig]) -> Self {
+        Webhooks(
+            configs
+                .iter()
+                .map(|config| {
+                    info!("forwarding reports to {}", config.url);
+                    (config.types.clone(), Webhook::new(config.clone()).start())
+                })
+                .collect(),
+        )
+    }
+
+    /// Whether any webhook takes reports of type `kind`.
+    pub fn wants(&self, kind: &str) -> bool {
+        self.0.iter().any(|(types, _)| accepts(types, kind))
+    }
+
+    pub fn forward(&self, report: Report) {
+        for (types, webhook) in &self.0 {
+            if accepts(types, report.kind) {
+                webhook.do_send(report.clone());
+            }
+        }
+    }
+}
+
+fn accepts(types: &[String], kind: &str) -> bool {
+    types.is_empty() || types.iter().any(|t| t == kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn type_filter() {
+        assert!(accepts(&[], "Login"));
+        let types = vec!["Login".to_string(), "P2PSnapshot".to_string()];
+        assert!(accepts(&types, "P2PSnapshot"));
+        assert!(!accepts(&types, "Stats"));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let config: WebhookConfig = ::serde_json::from_value(json!({
+            "url": "http://127.0.0.1:1/",
+            "flush_interval_secs": 5
+        }))
+        .unwrap();
+        assert_eq!(config.batch_size, 100);
+
+        let mut webhook = Webhook::new(config);
+        webhook.failures = 1;
+        assert_eq!(webhook.backoff(), Duration::from_secs(10));
+        webhook.failures = 3;
+        assert_eq!(webhook.backoff(), Duration::from_secs(40));
+        webhook.failures = 30;
+        assert_eq!(webhook.backoff(), Duration::from_secs(MAX_BACKOFF_SECS));
+    }
+}